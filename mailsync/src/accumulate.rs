@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::ops::BitOr;
+
+use tokio_imap::types::{AttributeValue, Response};
+use tokio_imap::ResponseData;
+use tracing::warn;
+
+use crate::{Flag, SyncError};
+
+/// Something learned from the responses to a FETCH command
+#[derive(Debug)]
+pub enum Fetched<T> {
+    /// All requested attributes for a message
+    Message(T),
+    Flags(FlagUpdate),
+}
+
+/// Flags the server reported for a message without being asked, like when another client
+/// changed them while a FETCH was running
+#[derive(Debug)]
+pub struct FlagUpdate {
+    pub seq: u32,
+    /// Only known if the server included it, or the same command returned the message
+    pub uid: Option<u32>,
+    pub mod_seq: Option<u64>,
+    pub flags: Vec<Flag>,
+}
+
+impl FlagUpdate {
+    fn from_response(rd: &ResponseData, uid: Option<u32>) -> Option<Self> {
+        let (seq, attr_vals) = match rd.parsed() {
+            Response::Fetch(seq, attr_vals) => (*seq, attr_vals),
+            _ => return None,
+        };
+
+        let mut update = FlagUpdate {
+            seq,
+            uid,
+            mod_seq: None,
+            flags: Vec::new(),
+        };
+        for val in attr_vals.iter() {
+            match *val {
+                AttributeValue::Uid(u) => update.uid = Some(u),
+                AttributeValue::ModSeq(ms) => update.mod_seq = Some(ms),
+                AttributeValue::Flags(ref fs) => {
+                    update.flags = fs.iter().filter_map(|&f| Flag::from_str(f)).collect();
+                }
+                _ => {}
+            }
+        }
+        Some(update)
+    }
+}
+
+/// What was left over when a FETCH command finished
+#[derive(Debug, Default)]
+pub struct Leftovers {
+    /// Flag updates for messages the command did not otherwise return
+    pub updates: Vec<FlagUpdate>,
+    /// UIDs of messages for which not all requested attributes came in
+    pub incomplete: Vec<u32>,
+}
+
+impl Leftovers {
+    /// An error listing the incomplete messages, if there were any
+    pub fn error(&self) -> Option<SyncError> {
+        if self.incomplete.is_empty() {
+            return None;
+        }
+
+        let msg = format!("incomplete FETCH responses for UIDs {:?}", self.incomplete);
+        Some(io::Error::new(io::ErrorKind::InvalidData, msg).into())
+    }
+}
+
+/// Groups the responses to a `UID FETCH` command by message
+///
+/// A message is complete once all requested attributes have come in, however the server
+/// spreads them over responses. FLAGS responses that are not part of the result, like
+/// those sent unsolicited after a change by another client, are passed on as updates.
+pub struct Collector {
+    requested: AttrSet,
+    parts: HashMap<u32, (AttrSet, Vec<ResponseData>)>,
+    /// UIDs of the messages completed so far, by sequence number
+    done: HashMap<u32, u32>,
+}
+
+impl Collector {
+    pub fn new(requested: AttrSet) -> Self {
+        Self {
+            requested,
+            parts: HashMap::new(),
+            done: HashMap::new(),
+        }
+    }
+
+    /// Add a response, returning the responses for a message once it is complete
+    pub fn push(&mut self, rd: ResponseData) -> Option<Fetched<Vec<ResponseData>>> {
+        let (seq, attrs) = match *rd.parsed() {
+            Response::Fetch(seq, ref attr_vals) => (seq, AttrSet::of(attr_vals)),
+            _ => return None,
+        };
+
+        // Responses to UID FETCH always include the UID, so updates without it were not
+        // asked for, just like updates for messages that are already complete
+        let done = self.done.get(&seq).copied();
+        if attrs.is_update()
+            && !self.parts.contains_key(&seq)
+            && (!attrs.contains(AttrSet::UID) || done.is_some())
+        {
+            return FlagUpdate::from_response(&rd, done).map(Fetched::Flags);
+        }
+
+        let entry = self.parts.entry(seq).or_insert_with(Default::default);
+        entry.0 = entry.0 | attrs;
+        entry.1.push(rd);
+        if !entry.0.contains(self.requested) {
+            return None;
+        }
+
+        let (_, rds) = self.parts.remove(&seq).unwrap();
+        if let Some(uid) = rds.iter().filter_map(response_uid).next() {
+            self.done.insert(seq, uid);
+        }
+        Some(Fetched::Message(rds))
+    }
+
+    /// Sort out the messages that never completed, once the command is done
+    ///
+    /// Messages for which only flags came in were not part of the result, so these are
+    /// returned as updates. The others are logged and returned as incomplete.
+    pub fn finish(self) -> Leftovers {
+        let requested = self.requested;
+        let mut leftovers = Leftovers::default();
+        for (seq, (attrs, rds)) in self.parts {
+            if attrs.is_update() {
+                let updates = rds
+                    .iter()
+                    .filter_map(|rd| FlagUpdate::from_response(rd, None));
+                leftovers.updates.extend(updates);
+                continue;
+            }
+
+            let uid = rds.iter().filter_map(response_uid).next();
+            let missing = requested.without(attrs);
+            warn!(seq, ?uid, ?missing, "incomplete FETCH response");
+            leftovers.incomplete.extend(uid);
+        }
+        leftovers.incomplete.sort_unstable();
+        leftovers
+    }
+}
+
+fn response_uid(rd: &ResponseData) -> Option<u32> {
+    match rd.parsed() {
+        Response::Fetch(_, attr_vals) => attr_vals.iter().find_map(|val| match *val {
+            AttributeValue::Uid(uid) => Some(uid),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// A set of FETCH attributes
+///
+/// Tracks which of the requested attributes have come in for a message.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct AttrSet(u16);
+
+impl AttrSet {
+    pub const UID: AttrSet = AttrSet(1);
+    pub const FLAGS: AttrSet = AttrSet(1 << 1);
+    pub const MOD_SEQ: AttrSet = AttrSet(1 << 2);
+    pub const INTERNAL_DATE: AttrSet = AttrSet(1 << 3);
+    pub const ENVELOPE: AttrSet = AttrSet(1 << 4);
+    /// `RFC822` or a `BODY[]` section
+    pub const SOURCE: AttrSet = AttrSet(1 << 5);
+    pub const HEADER: AttrSet = AttrSet(1 << 6);
+    pub const STRUCTURE: AttrSet = AttrSet(1 << 7);
+    pub const SIZE: AttrSet = AttrSet(1 << 8);
+    pub const GM_MSGID: AttrSet = AttrSet(1 << 9);
+    pub const GM_THRID: AttrSet = AttrSet(1 << 10);
+    pub const GM_LABELS: AttrSet = AttrSet(1 << 11);
+
+    /// All three Gmail attributes
+    pub const GMAIL: AttrSet = AttrSet(Self::GM_MSGID.0 | Self::GM_THRID.0 | Self::GM_LABELS.0);
+
+    /// Attributes that servers send unsolicited when another client changes a message
+    const UPDATES: AttrSet = AttrSet(Self::UID.0 | Self::FLAGS.0 | Self::MOD_SEQ.0);
+
+    const NAMES: [&'static str; 12] = [
+        "UID",
+        "FLAGS",
+        "MODSEQ",
+        "INTERNALDATE",
+        "ENVELOPE",
+        "BODY[]",
+        "RFC822.HEADER",
+        "BODYSTRUCTURE",
+        "RFC822.SIZE",
+        "X-GM-MSGID",
+        "X-GM-THRID",
+        "X-GM-LABELS",
+    ];
+
+    /// The attributes contained in a single FETCH response
+    pub fn of(vals: &[AttributeValue]) -> AttrSet {
+        use AttributeValue::*;
+        vals.iter().fold(AttrSet::default(), |set, val| {
+            set | match *val {
+                Uid(_) => AttrSet::UID,
+                Flags(_) => AttrSet::FLAGS,
+                ModSeq(_) => AttrSet::MOD_SEQ,
+                InternalDate(_) => AttrSet::INTERNAL_DATE,
+                Envelope(_) => AttrSet::ENVELOPE,
+                Rfc822(_) | BodySection { .. } => AttrSet::SOURCE,
+                Rfc822Header(_) => AttrSet::HEADER,
+                BodyStructure(_) => AttrSet::STRUCTURE,
+                Rfc822Size(_) => AttrSet::SIZE,
+                GmailMsgId(_) => AttrSet::GM_MSGID,
+                GmailThrId(_) => AttrSet::GM_THRID,
+                GmailLabels(_) => AttrSet::GM_LABELS,
+                _ => AttrSet::default(),
+            }
+        })
+    }
+
+    pub fn contains(self, other: AttrSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Attributes in `self` that are not in `other`
+    pub fn without(self, other: AttrSet) -> AttrSet {
+        AttrSet(self.0 & !other.0)
+    }
+
+    /// Whether this only holds attributes of a flag update, like `FLAGS` and `MODSEQ`
+    pub fn is_update(self) -> bool {
+        self.contains(AttrSet::FLAGS) && AttrSet::UPDATES.contains(self)
+    }
+}
+
+impl BitOr for AttrSet {
+    type Output = AttrSet;
+
+    fn bitor(self, rhs: AttrSet) -> AttrSet {
+        AttrSet(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for AttrSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = AttrSet::NAMES
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, name)| name);
+        f.debug_set().entries(names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio_imap::proto::ImapCodec;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+
+    #[test]
+    fn split_responses() {
+        let mut collector = Collector::new(AttrSet::UID | AttrSet::FLAGS | AttrSet::SIZE);
+        assert!(collector
+            .push(response("* 1 FETCH (UID 10 FLAGS (\\Seen))"))
+            .is_none());
+        match collector.push(response("* 1 FETCH (RFC822.SIZE 1234)")) {
+            Some(Fetched::Message(rds)) => {
+                assert_eq!(rds.len(), 2);
+                assert_eq!(response_uid(&rds[0]), Some(10));
+            }
+            other => panic!("expected message, got {:?}", other),
+        }
+
+        let leftovers = collector.finish();
+        assert!(leftovers.updates.is_empty());
+        assert!(leftovers.incomplete.is_empty());
+    }
+
+    #[test]
+    fn unsolicited_flags() {
+        let mut collector = Collector::new(AttrSet::UID | AttrSet::FLAGS | AttrSet::SIZE);
+        match collector.push(response("* 3 FETCH (FLAGS (\\Flagged) MODSEQ (12))")) {
+            Some(Fetched::Flags(update)) => {
+                assert_eq!(update.seq, 3);
+                assert_eq!(update.uid, None);
+                assert_eq!(update.mod_seq, Some(12));
+                assert_eq!(update.flags, vec![Flag::Flagged]);
+            }
+            other => panic!("expected flags, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn completed_then_updated() {
+        let mut collector = Collector::new(AttrSet::UID | AttrSet::FLAGS | AttrSet::SIZE);
+        let first = "* 1 FETCH (UID 10 FLAGS () RFC822.SIZE 1234)";
+        assert!(matches!(
+            collector.push(response(first)),
+            Some(Fetched::Message(_))
+        ));
+
+        // Without the UID, it is filled in from the completed message
+        match collector.push(response("* 1 FETCH (FLAGS (\\Seen))")) {
+            Some(Fetched::Flags(update)) => {
+                assert_eq!(update.uid, Some(10));
+                assert_eq!(update.flags, vec![Flag::Seen]);
+            }
+            other => panic!("expected flags, got {:?}", other),
+        }
+
+        // With the UID, it would otherwise look like the start of another result
+        match collector.push(response("* 1 FETCH (UID 10 FLAGS (\\Seen \\Answered))")) {
+            Some(Fetched::Flags(update)) => {
+                assert_eq!(update.uid, Some(10));
+                assert_eq!(update.flags, vec![Flag::Seen, Flag::Answered]);
+            }
+            other => panic!("expected flags, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn finish_sorts_out_leftovers() {
+        let mut collector = Collector::new(AttrSet::UID | AttrSet::FLAGS | AttrSet::SIZE);
+        assert!(collector
+            .push(response("* 2 FETCH (UID 20 RFC822.SIZE 10)"))
+            .is_none());
+        assert!(collector
+            .push(response("* 1 FETCH (UID 10 FLAGS (\\Seen))"))
+            .is_none());
+
+        let leftovers = collector.finish();
+        assert_eq!(leftovers.incomplete, vec![20]);
+        assert_eq!(leftovers.updates.len(), 1);
+        assert_eq!(leftovers.updates[0].seq, 1);
+        assert!(leftovers.error().is_some());
+    }
+
+    #[test]
+    fn attr_set() {
+        let update = AttrSet::UID | AttrSet::FLAGS | AttrSet::MOD_SEQ;
+        assert!(update.is_update());
+        assert!(AttrSet::FLAGS.is_update());
+        assert!(!AttrSet::UID.is_update());
+        assert!(!(AttrSet::FLAGS | AttrSet::SIZE).is_update());
+
+        assert!(AttrSet::GMAIL.contains(AttrSet::GM_THRID));
+        assert!(!AttrSet::GMAIL.contains(AttrSet::GM_THRID | AttrSet::UID));
+        assert!(
+            AttrSet::GMAIL.without(AttrSet::GM_LABELS) == AttrSet::GM_MSGID | AttrSet::GM_THRID
+        );
+        assert_eq!(format!("{:?}", update), r#"{"UID", "FLAGS", "MODSEQ"}"#);
+
+        let rd = response("* 1 FETCH (UID 1 RFC822.SIZE 10 X-GM-MSGID 123)");
+        match rd.parsed() {
+            Response::Fetch(_, vals) => {
+                assert!(AttrSet::of(vals) == AttrSet::UID | AttrSet::SIZE | AttrSet::GM_MSGID)
+            }
+            _ => panic!("expected FETCH"),
+        }
+    }
+
+    /// Parse a single untagged response as it would come from the server
+    fn response(line: &str) -> ResponseData {
+        let mut buf = BytesMut::from(format!("{}\r\n", line).as_bytes());
+        ImapCodec::default().decode(&mut buf).unwrap().unwrap()
+    }
+}
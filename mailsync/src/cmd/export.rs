@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use email_parser::Message;
+use postgres::{Client, NoTls};
+use structopt::StructOpt;
+use tracing::{debug_span, info, warn};
+
+use mailsync::maildir::{label_folder, Maildir};
+use mailsync::mbox::{format_labels, GmailHeaders, Writer};
+use mailsync::{logging, Config, Flag, SyncError};
+
+pub fn run(config: &Config, dry_run: bool, options: Options) {
+    crate::exit_on_error("export", export(config, dry_run, options));
+}
+
+fn export(config: &Config, dry_run: bool, options: Options) -> Result<(), SyncError> {
+    let mut conn = Client::connect(&config.store.uri, NoTls)?;
+    let mut tx = conn.transaction()?;
+    let portal = tx.bind(
+        "SELECT m.id, m.dt, m.bytes, m.flags, m.thrid, \
+             array_remove(array_agg(l.name ORDER BY l.name), NULL) \
+             FROM messages m \
+             LEFT JOIN message_labels ml ON ml.message = m.id \
+             LEFT JOIN labels l ON l.id = ml.label \
+             WHERE ($1::timestamptz IS NULL OR m.dt >= $1) \
+             AND ($2::timestamptz IS NULL OR m.dt < $2) \
+             GROUP BY m.id \
+             HAVING ($3::text IS NULL OR $3 = ANY(array_agg(l.name))) \
+             ORDER BY m.dt, m.id",
+        &[&options.since, &options.until, &options.label],
+    )?;
+
+    let mut sink = match (dry_run, options.format) {
+        (true, _) => Sink::DryRun,
+        (false, ExportFormat::Mbox) => {
+            if options.per_label {
+                fs::create_dir_all(&options.out)?;
+            }
+            Sink::Mbox {
+                files: MboxFiles::default(),
+            }
+        }
+        (false, ExportFormat::Maildir) => Sink::Maildir {
+            created: HashSet::new(),
+        },
+    };
+
+    let mut exported = 0;
+    let bar = logging::progress(None, "messages");
+    loop {
+        let rows = tx.query_portal(&portal, BATCH_SIZE)?;
+        if rows.is_empty() {
+            break;
+        }
+
+        let span = debug_span!("batch", offset = exported);
+        let _enter = span.enter();
+        for row in rows {
+            let id: i32 = row.get(0);
+            let bytes = match row.get(2) {
+                Some(bytes) => bytes,
+                None => {
+                    warn!(id, "skipping message without body, run `fill-bodies` first");
+                    continue;
+                }
+            };
+
+            let flags: Option<Vec<Flag>> = row.get(3);
+            let thrid: Option<i64> = row.get(4);
+            let msg = Exported {
+                id,
+                dt: row.get(1),
+                bytes,
+                flags: flags.unwrap_or_default(),
+                thread_id: thrid.map(|id| id as u64),
+                labels: row.get(5),
+            };
+
+            sink.write(&options, &msg);
+            exported += 1;
+            bar.inc(1);
+        }
+    }
+    bar.finish_and_clear();
+    sink.finish();
+    info!(messages = exported, dry_run, "export done");
+    Ok(())
+}
+
+enum Sink {
+    Mbox { files: MboxFiles },
+    Maildir { created: HashSet<PathBuf> },
+    DryRun,
+}
+
+impl Sink {
+    fn write(&mut self, options: &Options, msg: &Exported) {
+        match self {
+            Sink::Mbox { files } => {
+                let mut headers = Vec::new();
+                if options.gmail_labels {
+                    if let Some(thrid) = msg.thread_id {
+                        headers.push((GmailHeaders::THREAD_ID, thrid.to_string()));
+                    }
+                    headers.push((GmailHeaders::LABELS, format_labels(&msg.labels, &msg.flags)));
+                }
+
+                let paths = if options.per_label {
+                    msg.labels_or_unlabeled()
+                        .map(|label| label_file(&options.out, label))
+                        .collect::<Vec<_>>()
+                } else {
+                    vec![options.out.clone()]
+                };
+
+                let sender = envelope_sender(&msg.bytes);
+                for path in paths {
+                    let result = files
+                        .get(&path)
+                        .and_then(|writer| writer.write(&sender, &msg.dt, &headers, &msg.bytes));
+                    if let Err(e) = result {
+                        warn!(id = msg.id, ?path, error = %e, "failed to write message");
+                    }
+                }
+            }
+            Sink::Maildir { created } => {
+                for label in msg.labels_or_unlabeled() {
+                    let path = label_folder(&options.out, label);
+                    let folder = Maildir::new(&path);
+                    if !created.contains(&path) {
+                        if let Err(e) = folder.create() {
+                            warn!(id = msg.id, %label, error = %e, "failed to create folder");
+                            continue;
+                        }
+                        created.insert(path);
+                    }
+
+                    if let Err(e) = folder.store(msg.id, &msg.dt, &msg.flags, &msg.bytes) {
+                        warn!(id = msg.id, %label, error = %e, "failed to write message");
+                    }
+                }
+            }
+            Sink::DryRun => {}
+        }
+    }
+
+    fn finish(self) {
+        if let Sink::Mbox { files } = self {
+            for (path, (writer, _)) in files.open {
+                if let Err(e) = writer.into_inner().flush() {
+                    warn!(?path, error = %e, "failed to flush mbox file");
+                }
+            }
+        }
+    }
+}
+
+/// The mbox files being written, of which only the most recently used are kept open
+///
+/// With `--per-label`, there may be more labels than the process can have open files.
+/// Files are truncated when first opened, and appended to when opened again.
+#[derive(Default)]
+struct MboxFiles {
+    /// Open files, with the use count when they were last written to
+    open: HashMap<PathBuf, (Writer<BufWriter<File>>, u64)>,
+    created: HashSet<PathBuf>,
+    uses: u64,
+}
+
+impl MboxFiles {
+    fn get(&mut self, path: &Path) -> io::Result<&mut Writer<BufWriter<File>>> {
+        self.uses += 1;
+        if !self.open.contains_key(path) {
+            if self.open.len() >= MAX_OPEN_FILES {
+                let oldest = self
+                    .open
+                    .iter()
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(path, _)| path.clone())
+                    .unwrap();
+                let (writer, _) = self.open.remove(&oldest).unwrap();
+                writer.into_inner().flush()?;
+            }
+
+            let file = match self.created.insert(path.to_path_buf()) {
+                true => File::create(path)?,
+                false => OpenOptions::new().append(true).open(path)?,
+            };
+            let writer = Writer::new(BufWriter::new(file));
+            self.open.insert(path.to_path_buf(), (writer, 0));
+        }
+
+        let (writer, used) = self.open.get_mut(path).unwrap();
+        *used = self.uses;
+        Ok(writer)
+    }
+}
+
+struct Exported {
+    id: i32,
+    dt: DateTime<FixedOffset>,
+    bytes: Vec<u8>,
+    flags: Vec<Flag>,
+    thread_id: Option<u64>,
+    labels: Vec<String>,
+}
+
+impl Exported {
+    fn labels_or_unlabeled(&self) -> impl Iterator<Item = &str> {
+        let unlabeled = if self.labels.is_empty() {
+            Some(UNLABELED)
+        } else {
+            None
+        };
+        self.labels.iter().map(|s| s.as_str()).chain(unlabeled)
+    }
+}
+
+/// Address for the `From ` line, which may not contain spaces
+fn envelope_sender(bytes: &[u8]) -> String {
+    let msg = Message::from_slice(bytes);
+    let from = match msg.headers().get_first("from") {
+        Some(from) => from.to_string(),
+        None => return DEFAULT_SENDER.into(),
+    };
+
+    let address = match (from.find('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &from[start + 1..end],
+        _ => from.trim(),
+    };
+
+    if address.is_empty() || address.contains(char::is_whitespace) {
+        DEFAULT_SENDER.into()
+    } else {
+        address.to_string()
+    }
+}
+
+fn label_file(dir: &Path, label: &str) -> PathBuf {
+    let name = label.trim_start_matches('\\').replace('/', ".");
+    dir.join(format!("{}.mbox", name))
+}
+
+fn parse_date(s: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
+    Ok(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+}
+
+#[derive(Debug)]
+enum ExportFormat {
+    Mbox,
+    Maildir,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mbox" => Ok(ExportFormat::Mbox),
+            "maildir" => Ok(ExportFormat::Maildir),
+            _ => Err(format!("unknown export format {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// mbox file or Maildir++ root to write; a directory for mbox with `--per-label`
+    out: PathBuf,
+    /// Output format: mbox (mboxrd) or maildir
+    #[structopt(long, default_value = "mbox")]
+    format: ExportFormat,
+    /// Only export messages with this label
+    #[structopt(long)]
+    label: Option<String>,
+    /// Only export messages from this date (YYYY-MM-DD) onwards
+    #[structopt(long, parse(try_from_str = parse_date))]
+    since: Option<DateTime<Utc>>,
+    /// Only export messages from before this date (YYYY-MM-DD)
+    #[structopt(long, parse(try_from_str = parse_date))]
+    until: Option<DateTime<Utc>>,
+    /// Write a separate mbox file for each label
+    #[structopt(long)]
+    per_label: bool,
+    /// Add X-GM-THRID and X-Gmail-Labels headers, like Gmail's Takeout
+    #[structopt(long)]
+    gmail_labels: bool,
+}
+
+/// File (mbox) or folder (Maildir) name for messages that don't have any labels
+const UNLABELED: &str = "Archive";
+const DEFAULT_SENDER: &str = "MAILER-DAEMON";
+const BATCH_SIZE: i32 = 1000;
+/// Limit on open mbox files, well below the usual limit of 1024 file descriptors
+const MAX_OPEN_FILES: usize = 64;
@@ -0,0 +1,84 @@
+use std::convert::TryFrom;
+use std::io;
+
+use futures::stream::TryStreamExt;
+use structopt::StructOpt;
+use tokio_imap::builders::CommandBuilder;
+use tracing::{info, info_span, warn, Instrument};
+
+use mailsync::{fetch, logging, login, metrics, schema, Config, SyncError, SYNC_FOLDER};
+
+pub async fn run(config: Config, dry_run: bool, options: Options) {
+    let span = info_span!("fill-bodies", account = %config.imap.account);
+    let result = fill_bodies(config, dry_run, options).instrument(span).await;
+    crate::exit_on_error("fill-bodies", result);
+}
+
+async fn fill_bodies(config: Config, dry_run: bool, options: Options) -> Result<(), SyncError> {
+    let db = schema::connect(&config.store.uri).await?;
+    if !dry_run {
+        schema::migrate_async(&db).await?;
+    }
+
+    // Recent messages are the most likely to be read, so fill those in first
+    let rows = db
+        .query(
+            "SELECT id, unid, size FROM messages WHERE bytes IS NULL AND unid IS NOT NULL \
+             ORDER BY unid DESC LIMIT $1",
+            &[&options.limit],
+        )
+        .await?;
+    let total = rows
+        .iter()
+        .map(|row| row.get::<_, Option<i32>>(2).unwrap_or(0) as u64)
+        .sum::<u64>();
+    if dry_run {
+        info!(
+            messages = rows.len(),
+            bytes = total,
+            "would download bodies"
+        );
+        return Ok(());
+    }
+
+    let auth = config.imap.auth()?;
+    let mut session = login(&config.imap, &auth).await?;
+    fetch::examine_sync_folder(&mut session.client).await?;
+
+    let chunk_len = u32::try_from(config.imap.max_in_flight)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "max_in_flight is too large"))?;
+    let bar = logging::progress_bytes(total);
+    let (mut filled, mut missing) = (0, 0);
+    for row in rows {
+        let (id, uid): (i32, i64) = (row.get(0), row.get(1));
+        match fetch::fill_body(&mut session.client, &db, id, uid as u32, chunk_len).await? {
+            Some(source) => {
+                bar.inc(source.len() as u64);
+                metrics::BYTES_FETCHED
+                    .with_label_values(&[SYNC_FOLDER])
+                    .inc_by(source.len() as u64);
+                filled += 1;
+            }
+            None => {
+                warn!(id, uid, "message is no longer on the server");
+                missing += 1;
+            }
+        }
+    }
+    bar.finish_and_clear();
+
+    let _ = session
+        .client
+        .call(CommandBuilder::close())
+        .try_collect::<Vec<_>>()
+        .await?;
+    info!(messages = filled, missing, "bodies downloaded");
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Download at most this many bodies
+    #[structopt(long)]
+    limit: Option<i64>,
+}
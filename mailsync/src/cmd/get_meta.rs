@@ -0,0 +1,76 @@
+use futures::stream::TryStreamExt;
+use structopt::StructOpt;
+use tokio_imap::builders::CommandBuilder;
+use tracing::{info, info_span, warn, Instrument};
+
+use mailsync::meta::{self, MetaStore};
+use mailsync::reconcile::{write_csv, RemoteMeta};
+use mailsync::{login, schema, Config, SyncError};
+
+pub async fn run(config: Config, dry_run: bool, options: Options) {
+    let span = info_span!("get-meta", account = %config.imap.account);
+    let result = get_meta(config, dry_run, options).instrument(span).await;
+    crate::exit_on_error("get-meta", result);
+}
+
+async fn get_meta(config: Config, dry_run: bool, options: Options) -> Result<(), SyncError> {
+    let db = schema::connect(&config.store.uri).await?;
+    if !dry_run {
+        schema::migrate_async(&db).await?;
+    }
+    let store = MetaStore::new(&db);
+
+    let auth = config.imap.auth()?;
+    let mut session = login(&config.imap, &auth).await?;
+    meta::enable_qresync(&mut session).await?;
+    if dry_run {
+        info!(
+            changes = store.pending_changes().await?,
+            "not pushing local changes"
+        );
+        let update = store.check(&mut session).await?;
+        info!(
+            full = update.full,
+            fetched = update.fetched,
+            removed = update.removed,
+            "not updating metadata"
+        );
+    } else {
+        let pushed = store.push_changes(&mut session).await?;
+        if pushed.pushed > 0 {
+            info!(changes = pushed.pushed, "pushed local changes");
+        }
+        if pushed.held > 0 {
+            warn!(changes = pushed.held, "some local changes remain queued");
+        }
+
+        let update = store.update(&mut session).await?;
+        info!(
+            full = update.full,
+            fetched = update.fetched,
+            removed = update.removed,
+            "metadata updated"
+        );
+    }
+
+    let _ = session
+        .client
+        .call(CommandBuilder::close())
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    if let Some(fname) = &options.csv {
+        let metas = store.all().await?;
+        let remote = metas.iter().map(RemoteMeta::from).collect::<Vec<_>>();
+        write_csv(fname, &remote)?;
+        info!(messages = remote.len(), path = %fname, "wrote CSV");
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Also write the metadata as CSV, in the format read by `reconcile`
+    #[structopt(long)]
+    csv: Option<String>,
+}
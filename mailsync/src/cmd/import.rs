@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
+
+use chrono::{DateTime, FixedOffset};
+use email_parser::Message;
+use memmap::Mmap;
+use postgres::{Client, NoTls};
+use ring::digest;
+use structopt::StructOpt;
+use tracing::{info, info_span, warn};
+
+use mailsync::import::{ImportMessage, Importer};
+use mailsync::maildir::Maildir;
+use mailsync::mbox::{Format, GmailHeaders, GmailMeta, Reader};
+use mailsync::{fuzzy_datetime_parser, logging, schema, Config, SyncError};
+
+pub fn mbox(config: &Config, dry_run: bool, options: MboxOptions) {
+    crate::exit_on_error("import-mbox", import_mbox(config, dry_run, options));
+}
+
+fn import_mbox(config: &Config, dry_run: bool, options: MboxOptions) -> Result<(), SyncError> {
+    let file = File::open(&options.mbox)?;
+    // Empty files can't be mapped, and hold no messages anyway
+    if file.metadata()?.len() == 0 {
+        info!(mbox = ?options.mbox, "no messages to import");
+        return Ok(());
+    }
+    let data = unsafe { Mmap::map(&file) }?;
+    let mut reader = Reader::new(&data, options.format);
+    if !options.no_gmail {
+        reader = reader.step(Box::new(GmailHeaders));
+    }
+
+    let mut conn = connect(config, dry_run)?;
+    let mut importer = conn.as_mut().map(Importer::new).transpose()?;
+
+    let span = info_span!("import", mbox = ?options.mbox);
+    let _enter = span.enter();
+    let mut i = 0;
+    let bar = logging::progress_bytes(data.len() as u64);
+    while let Some(entry) = reader.next() {
+        bar.set_position(reader.position() as u64);
+        i += 1;
+
+        let dt = match entry.date().or_else(|| header_date(&entry.message)) {
+            Some(dt) => dt,
+            None => {
+                let from_line = String::from_utf8_lossy(entry.from_line);
+                warn!(index = i, %from_line, "no usable date");
+                continue;
+            }
+        };
+
+        let gmail = GmailMeta::from_entry(&entry);
+        let msg = ImportMessage {
+            dt,
+            bytes: &entry.message,
+            thread_id: gmail.thread_id,
+            gmail_id: gmail.message_id,
+            flags: &gmail.flags,
+            labels: &gmail.labels,
+        };
+        if let Some(importer) = importer.as_mut() {
+            if let Err(e) = importer.insert(&msg) {
+                warn!(index = i, error = %e, "failed to import message");
+            }
+        }
+    }
+    bar.finish_and_clear();
+    info!(messages = i, dry_run, "import done");
+    Ok(())
+}
+
+pub fn maildir(config: &Config, dry_run: bool, options: MaildirOptions) {
+    crate::exit_on_error("import-maildir", import_maildir(config, dry_run, options));
+}
+
+fn import_maildir(
+    config: &Config,
+    dry_run: bool,
+    options: MaildirOptions,
+) -> Result<(), SyncError> {
+    // Messages in the root folder are in the INBOX, subfolders map to labels
+    let root = Maildir::new(&options.maildir);
+    let mut folders = root.subfolders().unwrap_or_else(|e| {
+        warn!(path = ?options.maildir, error = %e, "failed to list subfolders");
+        Vec::new()
+    });
+    folders.insert(0, ("\\Inbox".to_string(), root));
+
+    let folders = folders
+        .into_iter()
+        .filter_map(|(label, folder)| match folder.entries() {
+            Ok(entries) => Some((label, entries)),
+            Err(e) => {
+                warn!(path = ?folder.path(), error = %e, "failed to list messages");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let total = folders
+        .iter()
+        .map(|(_, entries)| entries.len())
+        .sum::<usize>();
+
+    let mut conn = connect(config, dry_run)?;
+    let mut importer = conn.as_mut().map(Importer::new).transpose()?;
+    let mut imported = HashMap::new();
+    let bar = logging::progress(Some(total as u64), "messages");
+    for (label, entries) in folders {
+        let span = info_span!("folder", %label);
+        let _enter = span.enter();
+        info!(messages = entries.len(), "importing folder");
+        for entry in entries {
+            bar.inc(1);
+
+            let bytes = match fs::read(&entry.path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!(path = ?entry.path, error = %e, "failed to read message");
+                    continue;
+                }
+            };
+
+            // The same message may be present in several folders (one per label). Distinct
+            // messages can share a Message-ID, so only identical files count as the same.
+            let hash = digest::digest(&digest::SHA256, &bytes).as_ref().to_vec();
+            if let Some(id) = imported.get(&hash) {
+                if let Some(importer) = importer.as_mut() {
+                    if let Err(e) = importer.add_label(*id, &label) {
+                        warn!(path = ?entry.path, error = %e, "failed to add label");
+                    }
+                }
+                continue;
+            }
+
+            let dt = match header_date(&bytes).or_else(|| entry.modified()) {
+                Some(dt) => dt,
+                None => {
+                    warn!(path = ?entry.path, "no usable date");
+                    continue;
+                }
+            };
+
+            let labels = [label.clone()];
+            let msg = ImportMessage {
+                dt,
+                bytes: &bytes,
+                thread_id: None,
+                gmail_id: None,
+                flags: &entry.flags,
+                labels: &labels,
+            };
+            let id = match importer.as_mut().map(|importer| importer.insert(&msg)) {
+                Some(Ok(id)) => id,
+                Some(Err(e)) => {
+                    warn!(path = ?entry.path, error = %e, "failed to import message");
+                    continue;
+                }
+                None => 0,
+            };
+            imported.insert(hash, id);
+        }
+    }
+    bar.finish_and_clear();
+    info!(messages = total, dry_run, "import done");
+    Ok(())
+}
+
+/// Connect to the database and bring the schema up to date, unless this is a dry run
+fn connect(config: &Config, dry_run: bool) -> Result<Option<Client>, postgres::Error> {
+    if dry_run {
+        return Ok(None);
+    }
+
+    let mut conn = Client::connect(&config.store.uri, NoTls)?;
+    schema::migrate(&mut conn)?;
+    Ok(Some(conn))
+}
+
+fn header_date(bytes: &[u8]) -> Option<DateTime<FixedOffset>> {
+    Message::from_slice(bytes)
+        .headers()
+        .get_first("date")
+        .and_then(|s| fuzzy_datetime_parser(&s))
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MboxOptions {
+    mbox: PathBuf,
+    /// mbox variant: mboxo, mboxrd, mboxcl or mboxcl2
+    #[structopt(long, default_value = "mboxrd")]
+    format: Format,
+    /// Keep Gmail's X-GM-THRID and X-Gmail-Labels headers in the message
+    #[structopt(long)]
+    no_gmail: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MaildirOptions {
+    maildir: PathBuf,
+}
@@ -0,0 +1,16 @@
+use postgres::{Client, NoTls};
+
+use mailsync::{schema, Config};
+
+pub fn run(config: &Config, dry_run: bool) {
+    if dry_run {
+        for statement in schema::MIGRATIONS {
+            println!("{};", statement);
+        }
+        return;
+    }
+
+    let result =
+        Client::connect(&config.store.uri, NoTls).and_then(|mut conn| schema::migrate(&mut conn));
+    crate::exit_on_error("migrate", result);
+}
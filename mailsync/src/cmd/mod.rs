@@ -0,0 +1,9 @@
+pub mod export;
+pub mod fill_bodies;
+pub mod get_meta;
+pub mod import;
+pub mod migrate;
+pub mod quarantine;
+pub mod reconcile;
+pub mod sync;
+pub mod verify;
@@ -0,0 +1,80 @@
+use postgres::{Client, NoTls};
+use structopt::StructOpt;
+
+use mailsync::{quarantine, schema, Config};
+
+pub fn run(config: &Config, dry_run: bool, options: Options) {
+    if let Command::Restore { ids, all } = &options.command {
+        if ids.is_empty() && !all {
+            eprintln!("specify message ids to restore, or --all");
+            std::process::exit(1);
+        }
+    }
+
+    crate::exit_on_error("quarantine", quarantine(config, dry_run, options));
+}
+
+fn quarantine(config: &Config, dry_run: bool, options: Options) -> Result<(), postgres::Error> {
+    let mut conn = Client::connect(&config.store.uri, NoTls)?;
+    schema::migrate(&mut conn)?;
+
+    let (ids, all) = match options.command {
+        Command::List => return list(&mut conn, |_| true),
+        Command::Restore { ids, all } => (ids, all),
+    };
+
+    if dry_run {
+        return list(&mut conn, |id| all || ids.contains(&id));
+    }
+
+    let ids = if all { None } else { Some(&ids[..]) };
+    let restored = quarantine::restore(&mut conn, ids)?;
+    println!("restored {} messages", restored.restored);
+    if !restored.skipped.is_empty() {
+        println!(
+            "{} messages are already in the archive and were left in quarantine:",
+            restored.skipped.len()
+        );
+        for id in restored.skipped {
+            println!("{}", id);
+        }
+    }
+    Ok(())
+}
+
+fn list(conn: &mut Client, filter: impl Fn(i32) -> bool) -> Result<(), postgres::Error> {
+    for msg in quarantine::list(conn)? {
+        if !filter(msg.id) {
+            continue;
+        }
+
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            msg.id,
+            msg.quarantined.format("%Y-%m-%d %H:%M:%S"),
+            msg.reason.as_deref().unwrap_or(""),
+            msg.mid.as_deref().unwrap_or(""),
+            msg.subject.as_deref().unwrap_or(""),
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// List quarantined messages
+    List,
+    /// Move quarantined messages back into the archive
+    Restore {
+        ids: Vec<i32>,
+        /// Restore all quarantined messages
+        #[structopt(long)]
+        all: bool,
+    },
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::process;
+
+use postgres::{Client, NoTls};
+use structopt::StructOpt;
+use tracing::{info, warn};
+
+use mailsync::reconcile::{self, parse_cascade, Engine, Matcher, Source};
+use mailsync::{quarantine, schema, Config, SyncError};
+
+pub fn run(config: &Config, dry_run: bool, options: Options) {
+    if options.quarantine && !options.cascade.contains(&Matcher::MessageId) {
+        eprintln!("--quarantine requires the mid matcher");
+        process::exit(1);
+    }
+
+    crate::exit_on_error("reconcile", reconcile(config, dry_run, options));
+}
+
+fn reconcile(config: &Config, dry_run: bool, options: Options) -> Result<(), SyncError> {
+    let remote = options.meta.load(config)?;
+    info!(messages = remote.len(), "loaded remote metadata");
+    let engine = Engine::new(options.cascade, remote)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut conn = Client::connect(&config.store.uri, NoTls)?;
+    if !dry_run {
+        schema::migrate(&mut conn)?;
+    }
+    let report = reconcile::run(&engine, &mut conn, dry_run)?;
+    print!("{}", report);
+    if let Some(fname) = &options.review {
+        report.write_review(fname)?;
+    }
+
+    if !options.quarantine {
+        return Ok(());
+    }
+
+    // Messages whose Message-ID is unknown on the server have probably been deleted there
+    let missing = report
+        .no_candidates
+        .iter()
+        .filter_map(|(id, mid)| match mid {
+            Some(mid) if mid.len() > 5 => Some((*id, mid)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("{} messages would be quarantined:", missing.len());
+        for (id, mid) in &missing {
+            println!("{}\t{}", id, mid);
+        }
+        return Ok(());
+    }
+
+    let mut quarantined = 0;
+    for (id, _) in &missing {
+        match quarantine::quarantine(&mut conn, *id, "not found on server") {
+            Ok(true) => quarantined += 1,
+            Ok(false) => {}
+            Err(e) => warn!(id, error = %e, "failed to quarantine message"),
+        }
+    }
+    println!(
+        "quarantined {} messages, use `mailsync quarantine restore` to undo",
+        quarantined
+    );
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Remote metadata: `store`, `imap` or `csv:PATH` for a file written by `get-meta --csv`
+    meta: Source,
+    /// Comma-separated matchers to apply in order: mid, date, subject, sender, size, hash
+    ///
+    /// Gmail message IDs (gmid) are always compared first, where both sides have them.
+    // The full path keeps structopt from taking `Vec` as a repeatable option
+    #[structopt(
+        long = "match",
+        default_value = "mid,subject,sender",
+        parse(try_from_str = parse_cascade)
+    )]
+    cascade: std::vec::Vec<Matcher>,
+    /// Write ambiguous matches to this CSV file for manual review
+    #[structopt(long)]
+    review: Option<String>,
+    /// Move messages whose Message-ID is not on the server into quarantine
+    #[structopt(long)]
+    quarantine: bool,
+}
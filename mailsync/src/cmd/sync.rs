@@ -0,0 +1,864 @@
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::Utc;
+use email_parser::Message;
+use futures::future::join_all;
+use futures::stream::TryStreamExt;
+use indicatif::ProgressBar;
+use structopt::StructOpt;
+use tokio::sync::{mpsc, oneshot, Mutex as AsyncMutex};
+use tokio_imap::builders::{self, CommandBuilder, FetchCommand};
+use tokio_imap::types::{Response, ResponseCode};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::Statement;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use mailsync::accumulate::{Fetched, FlagUpdate, Leftovers};
+use mailsync::connection::Session;
+use mailsync::credentials::Auth;
+use mailsync::fetch::{self, Batch};
+use mailsync::{
+    logging, login, metrics, schema, Config, MessageMeta, ResponseAccumulator, SyncError,
+    SYNC_FOLDER,
+};
+
+pub async fn run(config: Config, dry_run: bool, mut options: Options) {
+    if !dry_run {
+        let result = match schema::connect(&config.store.uri).await {
+            Ok(db) => schema::migrate_async(&db).await,
+            Err(e) => Err(e),
+        };
+        crate::exit_on_error("migration", result);
+    }
+
+    if let Some(addr) = options.metrics {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr).await {
+                error!(error = %e, "metrics server failed");
+            }
+        });
+    }
+
+    // Resolve credentials once, so that commands aren't run again for every sync
+    let auth = match config.imap.auth() {
+        Ok(auth) => auth,
+        Err(e) => {
+            error!(error = %e, "failed to get credentials");
+            process::exit(1);
+        }
+    };
+
+    let max_connections = config.imap.max_connections;
+    if options.connections > max_connections {
+        warn!(
+            connections = options.connections,
+            max_connections, "limiting connections to the configured maximum"
+        );
+        options.connections = max_connections;
+    }
+
+    let span = info_span!("sync", account = %config.imap.account, folder = FOLDER);
+    loop {
+        // Connect for every sync, so that a dropped connection doesn't fail all later ones
+        let result = match schema::connect(&config.store.uri).await {
+            Ok(db) => {
+                sync(&config, &auth, &db, dry_run, &options)
+                    .instrument(span.clone())
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            metrics::ERRORS.with_label_values(&[e.kind()]).inc();
+            error!(error = ?e, "sync failed");
+            if options.interval.is_none() {
+                process::exit(1);
+            }
+        }
+
+        match options.interval {
+            Some(secs) => tokio::time::delay_for(Duration::from_secs(secs)).await,
+            None => break,
+        }
+    }
+}
+
+async fn sync(
+    config: &Config,
+    auth: &Auth,
+    db: &tokio_postgres::Client,
+    dry_run: bool,
+    options: &Options,
+) -> Result<(), SyncError> {
+    let mut session = login(&config.imap, auth).await?;
+
+    // All messages up to the checkpoint are stored. Above it, an interrupted sync may have
+    // stored some messages but not others, so only the missing ones are fetched.
+    let checkpoint = db
+        .query_opt(
+            "SELECT checkpoint FROM sync_status WHERE folder = $1",
+            &[&FOLDER],
+        )
+        .await;
+    let checkpoint: Option<i64> = match checkpoint {
+        Ok(row) => row.and_then(|row| row.get(0)),
+        // Dry runs don't migrate, so the table or column may not be there yet
+        Err(e) if dry_run && is_undefined(&e) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let checkpoint = match checkpoint {
+        Some(uid) => uid as u32,
+        // Synced before checkpoints were kept, when messages were stored in UID order
+        None => {
+            let max_uid: Option<i64> = db
+                .query_one("SELECT MAX(unid) FROM messages", &[])
+                .await?
+                .get(0);
+            max_uid.unwrap_or(0) as u32
+        }
+    };
+    let stored = db
+        .query(
+            "SELECT unid FROM messages WHERE unid > $1",
+            &[&(checkpoint as i64)],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get::<_, i64>(0) as u32)
+        .collect::<HashSet<_>>();
+
+    let uid_next = examine(&mut session).await?;
+    let mut sizes = fetch::sizes(&mut session.client, checkpoint + 1).await?;
+    sizes.retain(|(uid, _)| !stored.contains(uid));
+    let total = sizes.iter().map(|&(_, size)| size as u64).sum::<u64>();
+    if dry_run {
+        info!(
+            from = checkpoint + 1,
+            messages = sizes.len(),
+            bytes = total,
+            "would fetch new messages"
+        );
+        return Ok(());
+    }
+
+    // Every connection holds a batch in memory until it is stored, so they share the limit
+    let max_in_flight = (config.imap.max_in_flight / options.connections.max(1)).max(1);
+    let batches: Vec<Batch> = match options.headers_only {
+        // Headers are small, so batches only need to be small enough for checkpointing
+        true => sizes
+            .chunks(HEADERS_PER_BATCH)
+            .map(|chunk| Batch::Messages(chunk[0].0..=chunk[chunk.len() - 1].0))
+            .collect(),
+        false => fetch::batches(&sizes, max_in_flight),
+    };
+
+    // There's no use for more connections than batches
+    let mut sessions = vec![session];
+    let more = options.connections.min(batches.len()).saturating_sub(1);
+    sessions.extend(more_sessions(config, auth, more).await);
+    info!(
+        from = checkpoint + 1,
+        messages = sizes.len(),
+        bytes = total,
+        connections = sessions.len(),
+        "fetching new messages"
+    );
+
+    let writers = options.writers.unwrap_or(options.connections).max(1);
+    let fetcher = Fetcher {
+        pool: WriterPool::new(&config.store.uri, writers).await?,
+        db,
+        bar: logging::progress_bytes(total),
+        checkpoints: Mutex::new(Checkpoints::new(checkpoint, &batches)),
+        queue: Mutex::new(batches.into_iter().enumerate().collect()),
+        stored,
+        max_in_flight,
+        headers_only: options.headers_only,
+        failed: AtomicBool::new(false),
+    };
+    let results = join_all(sessions.iter_mut().map(|session| fetcher.worker(session))).await;
+    fetcher.bar.finish_and_clear();
+    results.into_iter().collect::<Result<(), _>>()?;
+    let last_uid = fetcher.checkpoints.lock().unwrap().current();
+    info!(messages = sizes.len(), "sync done");
+
+    for session in &mut sessions {
+        let _ = session
+            .client
+            .call(CommandBuilder::close())
+            .try_collect::<Vec<_>>()
+            .await?;
+    }
+
+    let now = Utc::now();
+    metrics::LAST_SUCCESS
+        .with_label_values(&[FOLDER])
+        .set(now.timestamp());
+    if let Some(uid_next) = uid_next {
+        metrics::UID_LAG
+            .with_label_values(&[FOLDER])
+            .set(uid_next as i64 - 1 - last_uid as i64);
+    }
+    let uid_next = uid_next.map(|next| next as i64);
+    db.execute(
+        "INSERT INTO sync_status (folder, last_success, uid_next, max_uid, checkpoint) \
+         VALUES ($1, $2, $3, $4, $4) \
+         ON CONFLICT (folder) DO UPDATE SET last_success = EXCLUDED.last_success, \
+         uid_next = EXCLUDED.uid_next, max_uid = EXCLUDED.max_uid, \
+         checkpoint = EXCLUDED.checkpoint",
+        &[&FOLDER, &now, &uid_next, &(last_uid as i64)],
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Keep running, syncing again after this many seconds
+    #[structopt(long)]
+    interval: Option<u64>,
+    /// Serve Prometheus metrics on this address
+    #[structopt(long)]
+    metrics: Option<SocketAddr>,
+    /// Only fetch headers and structure, leaving bodies for `fill-bodies` or the web app
+    #[structopt(long)]
+    headers_only: bool,
+    /// Number of IMAP connections to fetch over, limited by `max_connections`
+    #[structopt(long, default_value = "1")]
+    connections: usize,
+    /// Number of database connections to store messages over [default: --connections]
+    #[structopt(long)]
+    writers: Option<usize>,
+}
+
+const FOLDER: &str = SYNC_FOLDER;
+
+/// Messages per batch when only fetching headers
+const HEADERS_PER_BATCH: usize = 1000;
+/// Jobs that may wait for each database writer before fetching has to wait
+const JOBS_PER_WRITER: usize = 16;
+
+/// Whether a query failed because a table or column doesn't exist
+fn is_undefined(e: &tokio_postgres::Error) -> bool {
+    match e.code() {
+        Some(code) => *code == SqlState::UNDEFINED_TABLE || *code == SqlState::UNDEFINED_COLUMN,
+        None => false,
+    }
+}
+
+/// Examine `FOLDER`, returning its UIDNEXT
+async fn examine(session: &mut Session) -> Result<Option<u32>, SyncError> {
+    let msgs = session
+        .client
+        .call(CommandBuilder::examine(FOLDER))
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(msgs
+        .iter()
+        .filter_map(|rd| match rd.parsed() {
+            Response::Data {
+                code: Some(ResponseCode::UidNext(next)),
+                ..
+            } => Some(*next),
+            _ => None,
+        })
+        .next())
+}
+
+/// Log in up to `n` more times, to fetch over several connections
+///
+/// Servers limit the number of connections per account, so a refused login only means
+/// fetching over fewer connections.
+async fn more_sessions(config: &Config, auth: &Auth, n: usize) -> Vec<Session> {
+    let mut sessions = Vec::with_capacity(n);
+    for _ in 0..n {
+        let session = async {
+            let mut session = login(&config.imap, auth).await?;
+            examine(&mut session).await?;
+            Ok::<_, SyncError>(session)
+        };
+        match session.await {
+            Ok(session) => sessions.push(session),
+            Err(e) => {
+                let connections = sessions.len() + 1;
+                warn!(error = ?e, connections, "failed to open another connection");
+                break;
+            }
+        }
+    }
+    sessions
+}
+
+/// State shared by the connections fetching new messages
+struct Fetcher<'a> {
+    pool: WriterPool,
+    db: &'a tokio_postgres::Client,
+    bar: ProgressBar,
+    checkpoints: Mutex<Checkpoints>,
+    /// Batches that no connection has taken yet, with their index
+    queue: Mutex<VecDeque<(usize, Batch)>>,
+    /// UIDs above the checkpoint that were already stored
+    stored: HashSet<u32>,
+    /// This connection's share of `ImapConfig::max_in_flight`
+    max_in_flight: usize,
+    headers_only: bool,
+    /// Set when a connection fails, so that the others stop taking batches
+    failed: AtomicBool,
+}
+
+impl<'a> Fetcher<'a> {
+    /// Fetch batches from the queue over one connection, until it's empty
+    async fn worker(&self, session: &mut Session) -> Result<(), SyncError> {
+        while !self.failed.load(Ordering::SeqCst) {
+            let next = self.queue.lock().unwrap().pop_front();
+            let (idx, batch) = match next {
+                Some(next) => next,
+                None => break,
+            };
+
+            let result = match self.batch(session, batch).await {
+                Ok(()) => self.complete(idx).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                // The other connections still finish their batch, so that they don't
+                // leave truncated messages behind
+                self.failed.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn accumulator(&self, session: &Session) -> ResponseAccumulator {
+        let acc = ResponseAccumulator::new(&session.capabilities);
+        match self.headers_only {
+            true => acc.headers_only(),
+            false => acc,
+        }
+    }
+
+    async fn batch(&self, session: &mut Session, batch: Batch) -> Result<(), SyncError> {
+        let acc = self.accumulator(session);
+        match batch {
+            Batch::Messages(uids) => {
+                let cmd = acc.build_command_attributes(CommandBuilder::uid_fetch().range(uids));
+                let (_, leftovers) = self.store(session, acc, cmd, None).await?;
+                self.apply_updates(leftovers.updates).await?;
+                if let Some((&first, rest)) = leftovers.incomplete.split_first() {
+                    // Servers may leave out attributes under load, so try these once more
+                    let mut uids = CommandBuilder::uid_fetch().num(first);
+                    for &uid in rest {
+                        uids = uids.num(uid);
+                    }
+                    let acc = self.accumulator(session);
+                    let cmd = acc.build_command_attributes(uids);
+                    let (_, leftovers) = self.store(session, acc, cmd, None).await?;
+                    if let Some(e) = leftovers.error() {
+                        return Err(e);
+                    }
+                    self.apply_updates(leftovers.updates).await?;
+                }
+                Ok(())
+            }
+            Batch::Large { uid, size } => {
+                let chunk_len = u32::try_from(self.max_in_flight).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "max_in_flight is too large")
+                })?;
+                let acc = acc.partial(chunk_len);
+                let cmd = acc.build_command_attributes(CommandBuilder::uid_fetch().num(uid));
+                // The insert and the appended chunks are one transaction on a single writer,
+                // so that an interrupted sync can't leave a truncated message behind. Dropping
+                // `chunks` without sending `Chunk::Done` rolls it back.
+                let (mut chunks, rest) = mpsc::channel(1);
+                let (mut stored, leftovers) = self.store(session, acc, cmd, Some(rest)).await?;
+                if let Some(e) = leftovers.error() {
+                    return Err(e);
+                }
+                match stored.pop() {
+                    Some((_, Some(_))) => {
+                        self.fetch_rest(session, uid, size, &mut chunks, chunk_len)
+                            .await?;
+                        let (done, committed) = oneshot::channel();
+                        if chunks.send(Chunk::Done(done)).await.is_err() {
+                            return Err(stopped());
+                        }
+                        committed.await.map_err(|_| stopped())??;
+                    }
+                    Some((_, None)) => {
+                        debug!(uid, "not fetching rest of previously imported message");
+                    }
+                    None => {}
+                }
+
+                // Only now, since the writer for this message doesn't take other jobs
+                // until it is committed
+                self.apply_updates(leftovers.updates).await
+            }
+        }
+    }
+
+    /// Mark a batch as done, saving the checkpoint if it moved
+    async fn complete(&self, idx: usize) -> Result<(), SyncError> {
+        let checkpoint = self.checkpoints.lock().unwrap().done(idx);
+        if let Some(uid) = checkpoint {
+            // Connections may save checkpoints out of order, so never move it back
+            self.db
+                .execute(
+                    "INSERT INTO sync_status (folder, checkpoint) VALUES ($1, $2) \
+                     ON CONFLICT (folder) DO UPDATE \
+                     SET checkpoint = GREATEST(sync_status.checkpoint, EXCLUDED.checkpoint)",
+                    &[&FOLDER, &(uid as i64)],
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Fetch messages with the given command and store them as they come in
+    ///
+    /// Returns the UID of each stored message, with its id if it was newly inserted, along
+    /// with the messages that came in incomplete and the flag updates the server sent along
+    /// the way. With `rest`, the (single) message is stored as `Job::Large`.
+    async fn store(
+        &self,
+        session: &mut Session,
+        acc: ResponseAccumulator,
+        cmd: FetchCommand<builders::fetch::Attributes>,
+        rest: Option<mpsc::Receiver<Chunk>>,
+    ) -> Result<(Vec<(u32, Option<i32>)>, Leftovers), SyncError> {
+        let (acc, replies, mut updates, _) = session
+            .client
+            .call(cmd)
+            .map_err(SyncError::from)
+            .try_fold(
+                (acc, Vec::new(), Vec::new(), rest),
+                |(acc, mut replies, mut updates, mut rest), rd| async move {
+                    let (new, item) = acc.push(rd);
+                    match item {
+                        Some(Fetched::Message(meta)) if self.stored.contains(&meta.uid) => {
+                            debug!(uid = meta.uid, "skipping previously stored message");
+                        }
+                        Some(Fetched::Message(meta)) => {
+                            debug!(uid = meta.uid, dt = %meta.dt, "storing message");
+                            let fetched = meta.header_source().len() as u64;
+                            self.bar.inc(meta.size.map_or(fetched, u64::from));
+                            metrics::MESSAGES_FETCHED.with_label_values(&[FOLDER]).inc();
+                            metrics::BYTES_FETCHED
+                                .with_label_values(&[FOLDER])
+                                .inc_by(fetched);
+                            let uid = meta.uid;
+                            let job = match rest.take() {
+                                Some(chunks) => Job::Large(meta, chunks),
+                                None => Job::Insert(meta),
+                            };
+                            replies.push((uid, self.pool.submit(job).await?));
+                        }
+                        Some(Fetched::Flags(update)) => updates.push(update),
+                        None => {}
+                    }
+                    Ok((new, replies, updates, rest))
+                },
+            )
+            .await?;
+
+        let mut stored = Vec::with_capacity(replies.len());
+        for (uid, reply) in replies {
+            stored.push((uid, wait(reply).await?));
+        }
+
+        let mut leftovers = acc.finish();
+        updates.append(&mut leftovers.updates);
+        leftovers.updates = updates;
+        Ok((stored, leftovers))
+    }
+
+    async fn apply_updates(&self, updates: Vec<FlagUpdate>) -> Result<(), SyncError> {
+        for update in updates {
+            self.pool.call(Job::Flags(update)).await?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the rest of a large message in chunks, passing them on to its writer
+    async fn fetch_rest(
+        &self,
+        session: &mut Session,
+        uid: u32,
+        size: u32,
+        chunks: &mut mpsc::Sender<Chunk>,
+        chunk_len: u32,
+    ) -> Result<(), SyncError> {
+        let mut offset = chunk_len;
+        while offset < size {
+            let data = fetch::chunk(&mut session.client, uid, offset, chunk_len).await?;
+            if data.is_empty() {
+                break;
+            }
+            let len = data.len();
+            if chunks.send(Chunk::Data(data)).await.is_err() {
+                return Err(stopped());
+            }
+            self.bar.inc(len as u64);
+            metrics::BYTES_FETCHED
+                .with_label_values(&[FOLDER])
+                .inc_by(len as u64);
+            offset += len as u32;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks which batches are done, to find the UID up to which all messages are stored
+struct Checkpoints {
+    /// The checkpoint before the first batch
+    start: u32,
+    /// The last UID of each batch, in order
+    ends: Vec<u32>,
+    done: Vec<bool>,
+    /// Number of batches at the start that are all done
+    complete: usize,
+}
+
+impl Checkpoints {
+    fn new(start: u32, batches: &[Batch]) -> Self {
+        Self {
+            start,
+            ends: batches.iter().map(Batch::last_uid).collect(),
+            done: vec![false; batches.len()],
+            complete: 0,
+        }
+    }
+
+    /// Mark a batch as done, returning the new checkpoint if it moved
+    fn done(&mut self, idx: usize) -> Option<u32> {
+        self.done[idx] = true;
+        let before = self.complete;
+        while self.complete < self.done.len() && self.done[self.complete] {
+            self.complete += 1;
+        }
+        match self.complete > before {
+            true => Some(self.current()),
+            false => None,
+        }
+    }
+
+    /// The UID up to which all messages are stored
+    fn current(&self) -> u32 {
+        match self.complete {
+            0 => self.start,
+            n => self.ends[n - 1],
+        }
+    }
+}
+
+type Reply = oneshot::Receiver<Result<Option<i32>, SyncError>>;
+
+/// Stores messages over a bounded number of database connections
+///
+/// Jobs wait in a queue of `JOBS_PER_WRITER` per writer, so that fetching slows down
+/// when the database can't keep up. The queue is bounded by number of jobs, not bytes,
+/// so it can hold that many complete messages.
+struct WriterPool {
+    jobs: mpsc::Sender<(Job, oneshot::Sender<Result<Option<i32>, SyncError>>)>,
+}
+
+/// Work for a database writer, see the `Writer` methods
+enum Job {
+    Insert(MessageMeta),
+    /// Insert the start of a large message, followed by the rest in the same transaction
+    Large(MessageMeta, mpsc::Receiver<Chunk>),
+    Flags(FlagUpdate),
+}
+
+/// The next part of a large message, see `Writer::large()`
+enum Chunk {
+    Data(Vec<u8>),
+    /// Commit the message, replying with the result
+    Done(oneshot::Sender<Result<(), SyncError>>),
+}
+
+impl WriterPool {
+    async fn new(uri: &str, size: usize) -> Result<Self, SyncError> {
+        let (jobs, queue) = mpsc::channel(size * JOBS_PER_WRITER);
+        let queue = Arc::new(AsyncMutex::new(queue));
+        for _ in 0..size {
+            let db = schema::connect(uri).await?;
+            let writer = Writer::new(db).await?;
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                loop {
+                    // Only hold the lock while waiting for a job, not while running it
+                    let next = queue.lock().await.recv().await;
+                    let (job, reply) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    match job {
+                        Job::Large(meta, chunks) => writer.large(&meta, chunks, reply).await,
+                        job => {
+                            let _ = reply.send(writer.run(job).await);
+                        }
+                    }
+                }
+            });
+        }
+        Ok(Self { jobs })
+    }
+
+    /// Queue a job, waiting for room in the queue if needed
+    async fn submit(&self, job: Job) -> Result<Reply, SyncError> {
+        let (tx, rx) = oneshot::channel();
+        match self.jobs.clone().send((job, tx)).await {
+            Ok(()) => Ok(rx),
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    /// Run a job and wait for its result
+    async fn call(&self, job: Job) -> Result<Option<i32>, SyncError> {
+        wait(self.submit(job).await?).await
+    }
+}
+
+async fn wait(reply: Reply) -> Result<Option<i32>, SyncError> {
+    reply.await.map_err(|_| stopped())?
+}
+
+fn stopped() -> SyncError {
+    io::Error::new(io::ErrorKind::BrokenPipe, "database writer stopped").into()
+}
+
+/// Prepared statements for storing fetched messages
+struct Writer {
+    db: tokio_postgres::Client,
+    insert: Statement,
+    append: Statement,
+    flags: Statement,
+    label: Statement,
+    link: Statement,
+}
+
+impl Writer {
+    async fn new(db: tokio_postgres::Client) -> Result<Writer, SyncError> {
+        // Messages imported from a Takeout archive are recognized by their Gmail message
+        // ID, so they get a UID instead of being stored twice. `xmax` is only zero for
+        // newly inserted rows, which tells them apart from such updated ones.
+        let insert = db
+            .prepare(
+                "INSERT INTO messages (unid, mod_seq, dt, subject, mid, bytes, flags, thrid, \
+                                       gm_msgid, headers, size, parts) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) \
+                 ON CONFLICT (gm_msgid) DO UPDATE SET unid = EXCLUDED.unid, \
+                 mod_seq = EXCLUDED.mod_seq, flags = EXCLUDED.flags \
+                 RETURNING id, xmax = 0",
+            )
+            .await?;
+        let append = db
+            .prepare("UPDATE messages SET bytes = bytes || $1 WHERE id = $2")
+            .await?;
+        let flags = db
+            .prepare(
+                "UPDATE messages SET flags = $1, mod_seq = GREATEST(mod_seq, $2) \
+                 WHERE unid = $3",
+            )
+            .await?;
+        let label = db
+            .prepare(
+                "INSERT INTO labels (name) VALUES ($1) \
+                 ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+            )
+            .await?;
+        let link = db
+            .prepare(
+                "INSERT INTO message_labels (message, label) VALUES ($1, $2) \
+                 ON CONFLICT DO NOTHING",
+            )
+            .await?;
+
+        Ok(Self {
+            db,
+            insert,
+            append,
+            flags,
+            label,
+            link,
+        })
+    }
+
+    async fn run(&self, job: Job) -> Result<Option<i32>, SyncError> {
+        match job {
+            Job::Insert(meta) => self.insert(&meta).await,
+            Job::Large(..) => unreachable!("large messages are stored by Writer::large()"),
+            Job::Flags(update) => self.update_flags(&update).await.map(|_| None),
+        }
+    }
+
+    /// Store a large message, appending chunks until told to commit
+    ///
+    /// The insert result is replied right away, so that the fetcher can skip the rest of
+    /// messages that were stored before. Everything is rolled back if the fetcher drops
+    /// `chunks` before sending `Chunk::Done`, or if an append fails.
+    async fn large(
+        &self,
+        meta: &MessageMeta,
+        mut chunks: mpsc::Receiver<Chunk>,
+        reply: oneshot::Sender<Result<Option<i32>, SyncError>>,
+    ) {
+        if let Err(e) = self.db.batch_execute("BEGIN").await {
+            let _ = reply.send(Err(e.into()));
+            return;
+        }
+
+        let id = match self.insert(meta).await {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                let _ = reply.send(self.end("COMMIT").await.map(|_| None));
+                return;
+            }
+            Err(e) => {
+                let _ = self.end("ROLLBACK").await;
+                let _ = reply.send(Err(e));
+                return;
+            }
+        };
+        let _ = reply.send(Ok(Some(id)));
+
+        let mut failed = None;
+        while let Some(chunk) = chunks.recv().await {
+            match chunk {
+                Chunk::Data(data) if failed.is_none() => {
+                    failed = self.append(id, &data).await.err();
+                }
+                Chunk::Data(_) => {}
+                Chunk::Done(done) => {
+                    let result = match failed {
+                        Some(e) => {
+                            let _ = self.end("ROLLBACK").await;
+                            Err(e)
+                        }
+                        None => self.end("COMMIT").await,
+                    };
+                    let _ = done.send(result);
+                    return;
+                }
+            }
+        }
+
+        debug!(uid = meta.uid, "rolling back incomplete message");
+        let _ = self.end("ROLLBACK").await;
+    }
+
+    async fn end(&self, statement: &str) -> Result<(), SyncError> {
+        self.db.batch_execute(statement).await?;
+        Ok(())
+    }
+
+    /// Store a message with its labels, returning its id if it was newly inserted
+    async fn insert(&self, meta: &MessageMeta) -> Result<Option<i32>, SyncError> {
+        let msg = Message::from_slice(meta.header_source());
+        let headers = msg.headers();
+        let row = self
+            .db
+            .query_one(
+                &self.insert,
+                &[
+                    &(meta.uid as i64),
+                    &(meta.mod_seq as i64),
+                    &meta.dt,
+                    &headers.get_first("subject").map(|s| s.to_string()),
+                    &headers.get_first("message-id").map(|s| s.to_string()),
+                    &meta.raw,
+                    &meta.flags,
+                    &meta.gm_thrid.map(|id| id as i64),
+                    &meta.gm_msgid.map(|id| id as i64),
+                    &meta.headers,
+                    &meta.size.map(|size| size as i32),
+                    &meta.parts,
+                ],
+            )
+            .await?;
+        let id: i32 = row.get(0);
+        for label in &meta.labels {
+            let label_id: i32 = self.db.query_one(&self.label, &[label]).await?.get(0);
+            self.db.execute(&self.link, &[&id, &label_id]).await?;
+        }
+
+        let inserted: bool = row.get(1);
+        Ok(if inserted { Some(id) } else { None })
+    }
+
+    /// Add a chunk to the source of a message stored by `insert()`
+    async fn append(&self, id: i32, data: &[u8]) -> Result<(), SyncError> {
+        self.db.execute(&self.append, &[&data, &id]).await?;
+        Ok(())
+    }
+
+    /// Apply flags the server reported for a message during a fetch
+    async fn update_flags(&self, update: &FlagUpdate) -> Result<(), SyncError> {
+        let uid = match update.uid {
+            Some(uid) => uid,
+            None => {
+                debug!(seq = update.seq, "ignoring flag update without UID");
+                return Ok(());
+            }
+        };
+
+        let mod_seq = update.mod_seq.unwrap_or(0) as i64;
+        self.db
+            .execute(&self.flags, &[&update.flags, &mod_seq, &(uid as i64)])
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints_in_order() {
+        let batches = [
+            Batch::Messages(11..=20),
+            Batch::Large { uid: 21, size: 100 },
+            Batch::Messages(22..=30),
+        ];
+        let mut checkpoints = Checkpoints::new(10, &batches);
+        assert_eq!(checkpoints.current(), 10);
+        assert_eq!(checkpoints.done(0), Some(20));
+        assert_eq!(checkpoints.done(1), Some(21));
+        assert_eq!(checkpoints.done(2), Some(30));
+        assert_eq!(checkpoints.current(), 30);
+    }
+
+    #[test]
+    fn checkpoints_out_of_order() {
+        let batches = [
+            Batch::Messages(11..=20),
+            Batch::Messages(21..=30),
+            Batch::Messages(31..=40),
+            Batch::Messages(41..=50),
+        ];
+        let mut checkpoints = Checkpoints::new(10, &batches);
+        // Later batches finishing first don't move the checkpoint past missing messages
+        assert_eq!(checkpoints.done(2), None);
+        assert_eq!(checkpoints.done(1), None);
+        assert_eq!(checkpoints.current(), 10);
+        // Until the first one is done, which completes all three at once
+        assert_eq!(checkpoints.done(0), Some(40));
+        assert_eq!(checkpoints.done(3), Some(50));
+    }
+
+    #[test]
+    fn checkpoints_without_batches() {
+        let checkpoints = Checkpoints::new(10, &[]);
+        assert_eq!(checkpoints.current(), 10);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::process;
+
+use postgres::{Client, NoTls};
+use structopt::StructOpt;
+use tracing::info;
+
+use mailsync::reconcile::Source;
+use mailsync::{Config, SyncError};
+
+pub fn run(config: &Config, options: Options) {
+    crate::exit_on_error("verify", verify(config, options));
+}
+
+/// Check that every message in the remote metadata is in the archive, by Message-ID
+fn verify(config: &Config, options: Options) -> Result<(), SyncError> {
+    let remote = options.source.load(config)?;
+    info!(messages = remote.len(), "loaded remote metadata");
+
+    let mut conn = Client::connect(&config.store.uri, NoTls)?;
+    let archived = conn
+        .query("SELECT mid FROM messages WHERE mid IS NOT NULL", &[])?
+        .into_iter()
+        .map(|row| row.get::<_, String>(0).trim().to_string())
+        .collect::<HashSet<_>>();
+
+    let (mut missing, mut unverified) = (0, 0);
+    for meta in &remote {
+        let mid = match &meta.mid {
+            Some(mid) => mid.trim(),
+            None => {
+                unverified += 1;
+                continue;
+            }
+        };
+
+        if !archived.contains(mid) {
+            missing += 1;
+            println!(
+                "missing: UID {}\t{}\t{}",
+                meta.uid,
+                mid,
+                meta.subject.as_deref().unwrap_or("")
+            );
+        }
+    }
+
+    println!(
+        "{} messages checked, {} missing, {} without Message-ID",
+        remote.len(),
+        missing,
+        unverified
+    );
+    if missing > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Options {
+    /// Remote metadata: `store`, `imap` or `csv:PATH` for a file written by `get-meta --csv`
+    #[structopt(default_value = "store")]
+    source: Source,
+}
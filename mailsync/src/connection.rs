@@ -0,0 +1,349 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::future::ready;
+use futures::stream::TryStreamExt;
+use ring::digest;
+use serde_derive::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_imap::builders::CommandBuilder;
+use tokio_imap::types::{Capability, Response};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+use crate::ImapConfig;
+
+/// An IMAP client over any of the supported transports
+pub type Client = tokio_imap::Client<Stream>;
+
+/// A logged-in client, along with what the server supports
+pub struct Session {
+    pub client: Client,
+    pub capabilities: Capabilities,
+}
+
+/// The IMAP extensions the server supports that we know how to use
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Per-message mod-seqs and `CHANGEDSINCE` (RFC 7162)
+    pub condstore: bool,
+    /// Quick resynchronization (RFC 7162), which implies CONDSTORE
+    pub qresync: bool,
+    /// Whether QRESYNC was enabled for this session, so that the server reports
+    /// expunged messages as VANISHED
+    pub qresync_enabled: bool,
+    pub idle: bool,
+    /// `UID EXPUNGE` and the UIDs of appended and copied messages (RFC 4315)
+    pub uidplus: bool,
+    /// `UID MOVE` (RFC 6851)
+    pub moves: bool,
+    /// Gmail's labels and message and thread IDs
+    pub gmail: bool,
+    pub compress: bool,
+    /// Non-synchronizing literals (RFC 7888)
+    pub literal_plus: bool,
+}
+
+impl Capabilities {
+    /// Ask the server for its capabilities
+    pub async fn fetch(client: &mut Client) -> io::Result<Self> {
+        client
+            .call(CommandBuilder::capability())
+            .try_fold(Self::default(), |mut caps, rd| {
+                if let Response::Capabilities(list) = rd.parsed() {
+                    for cap in list {
+                        if let Capability::Atom(name) = cap {
+                            caps.insert(name);
+                        }
+                    }
+                }
+                ready(Ok(caps))
+            })
+            .await
+    }
+
+    fn insert(&mut self, name: &str) {
+        match name.to_ascii_uppercase().as_str() {
+            "CONDSTORE" => self.condstore = true,
+            "QRESYNC" => {
+                self.qresync = true;
+                self.condstore = true;
+            }
+            "IDLE" => self.idle = true,
+            "UIDPLUS" => self.uidplus = true,
+            "MOVE" => self.moves = true,
+            "X-GM-EXT-1" => self.gmail = true,
+            "COMPRESS=DEFLATE" => self.compress = true,
+            "LITERAL+" => self.literal_plus = true,
+            _ => {}
+        }
+    }
+}
+
+/// How the connection to the server is secured
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    /// TLS from the start, usually on port 993
+    Implicit,
+    /// Plain connection upgraded with STARTTLS, usually on port 143
+    StartTls,
+    /// No encryption at all; only meant for local test servers
+    Plain,
+}
+
+impl Security {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Security::Implicit => 993,
+            Security::StartTls | Security::Plain => 143,
+        }
+    }
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Security::Implicit
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file with CA certificates to trust instead of the built-in roots
+    pub ca_file: Option<PathBuf>,
+    /// PEM file with a client certificate chain to present to the server
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the private key (PKCS#8 or RSA) for `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Hex-encoded SHA-256 fingerprints of accepted server certificates
+    ///
+    /// When set, the server certificate only has to match one of these, so that
+    /// self-signed certificates can be used.
+    pub pinned: Vec<String>,
+}
+
+impl TlsConfig {
+    fn client_config(&self) -> io::Result<ClientConfig> {
+        let mut config = ClientConfig::new();
+        match &self.ca_file {
+            Some(path) => {
+                let mut reader = BufReader::new(File::open(path)?);
+                let (valid, _) = config
+                    .root_store
+                    .add_pem_file(&mut reader)
+                    .map_err(|_| invalid(path, "invalid CA bundle"))?;
+                if valid == 0 {
+                    return Err(invalid(path, "no CA certificates found"));
+                }
+            }
+            None => config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+                    .map_err(|_| invalid(cert, "invalid certificate"))?;
+                let key = read_key(key)?;
+                config
+                    .set_single_client_cert(certs, key)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "client_cert and client_key must be configured together",
+                ))
+            }
+        }
+
+        if !self.pinned.is_empty() {
+            let pins = self
+                .pinned
+                .iter()
+                .map(|pin| pin.replace(':', "").to_lowercase())
+                .collect();
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(PinnedVerifier(pins)));
+        }
+
+        Ok(config)
+    }
+}
+
+fn read_key(path: &Path) -> io::Result<tokio_rustls::rustls::PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| invalid(path, "invalid private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+            .map_err(|_| invalid(path, "invalid private key"))?;
+    }
+    keys.pop()
+        .ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn invalid(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    )
+}
+
+/// Accepts only server certificates with one of the pinned fingerprints
+struct PinnedVerifier(Vec<String>);
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        _: &RootCertStore,
+        presented: &[Certificate],
+        _: DNSNameRef,
+        _: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let leaf = presented.first().ok_or(TLSError::NoCertificatesPresented)?;
+        let fingerprint = digest::digest(&digest::SHA256, &leaf.0)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        if self.0.contains(&fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::General(format!(
+                "certificate fingerprint {} is not pinned",
+                fingerprint
+            )))
+        }
+    }
+}
+
+/// Connect to the configured server, setting up TLS as configured
+///
+/// The greeting and STARTTLS exchange are handled here, so the returned stream is
+/// ready for the login.
+pub async fn connect(config: &ImapConfig) -> io::Result<Stream> {
+    let port = config.port();
+    debug!(server = %config.server, port, security = ?config.security, "connecting");
+    let mut tcp = TcpStream::connect((config.server.as_str(), port)).await?;
+
+    let stream = match config.security {
+        Security::Plain => {
+            greeting(&mut tcp).await?;
+            Stream::Plain(tcp)
+        }
+        Security::StartTls => {
+            greeting(&mut tcp).await?;
+            tcp.write_all(b"tls0 STARTTLS\r\n").await?;
+            loop {
+                let line = read_line(&mut tcp).await?;
+                if line.starts_with("tls0 ") {
+                    if !line[5..].starts_with("OK") {
+                        return Err(refused(&line));
+                    }
+                    break;
+                }
+            }
+            Stream::Tls(Box::new(handshake(config, tcp).await?))
+        }
+        Security::Implicit => {
+            let mut tls = handshake(config, tcp).await?;
+            greeting(&mut tls).await?;
+            Stream::Tls(Box::new(tls))
+        }
+    };
+
+    Ok(stream)
+}
+
+async fn handshake(config: &ImapConfig, tcp: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+    let tls = config.tls.client_config()?;
+    let name = DNSNameRef::try_from_ascii_str(&config.server)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server name"))?;
+    TlsConnector::from(Arc::new(tls)).connect(name, tcp).await
+}
+
+async fn greeting<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<()> {
+    let line = read_line(stream).await?;
+    debug!(greeting = %line.trim_end());
+    if line.starts_with("* OK") || line.starts_with("* PREAUTH") {
+        Ok(())
+    } else {
+        Err(refused(&line))
+    }
+}
+
+/// Read a single response line, byte by byte so nothing after it is consumed
+pub(crate) async fn read_line<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n") {
+        let mut byte = [0];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn refused(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        format!("unexpected server response: {}", line.trim_end()),
+    )
+}
+
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
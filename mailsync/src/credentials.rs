@@ -0,0 +1,202 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_derive::Deserialize;
+use zeroize::Zeroize;
+
+use crate::oauth2::{OAuth2Config, Session};
+
+/// Resolved credentials for logging in to the IMAP server
+pub enum Auth {
+    Password(Password),
+    OAuth2(Session),
+}
+
+impl Auth {
+    pub fn new(
+        credentials: &Credentials,
+        oauth2: Option<&OAuth2Config>,
+        account: &str,
+    ) -> Result<Self, CredentialError> {
+        let secret = credentials.password(account)?;
+        Ok(match oauth2 {
+            // With OAuth2, the configured password is the refresh token
+            Some(config) => Auth::OAuth2(Session::new(config.clone(), secret)),
+            None => Auth::Password(secret),
+        })
+    }
+}
+
+/// Where to get the IMAP password from; exactly one source should be configured
+#[derive(Debug, Default, Deserialize)]
+pub struct Credentials {
+    /// The password itself, in plain text
+    pub password: Option<Password>,
+    /// Name of an environment variable holding the password
+    pub password_env: Option<String>,
+    /// Path to a file containing the password, which only its owner may access
+    pub password_file: Option<PathBuf>,
+    /// Shell command printing the password (like `pass show mail/imap`)
+    pub password_command: Option<String>,
+    /// Get the password from the Secret Service, keyed by the `mailsync` service and account
+    #[serde(default)]
+    pub password_keyring: bool,
+}
+
+impl Credentials {
+    /// Resolve the configured source into a password
+    ///
+    /// Commands are executed and files read on each call, so callers should hold on to
+    /// the result rather than calling this for each connection.
+    pub fn password(&self, account: &str) -> Result<Password, CredentialError> {
+        let configured = [
+            self.password.is_some(),
+            self.password_env.is_some(),
+            self.password_file.is_some(),
+            self.password_command.is_some(),
+            self.password_keyring,
+        ];
+        match configured.iter().filter(|&&c| c).count() {
+            0 => return Err(CredentialError::Missing),
+            1 => {}
+            _ => return Err(CredentialError::Ambiguous),
+        }
+
+        if let Some(password) = &self.password {
+            Ok(password.clone())
+        } else if let Some(var) = &self.password_env {
+            match std::env::var(var) {
+                Ok(val) => Ok(Password(val)),
+                Err(_) => Err(CredentialError::Env(var.clone())),
+            }
+        } else if let Some(path) = &self.password_file {
+            read_file(path)
+        } else if let Some(cmd) = &self.password_command {
+            run_command(cmd)
+        } else {
+            keyring::Keyring::new(KEYRING_SERVICE, account)
+                .get_password()
+                .map(Password)
+                .map_err(|e| CredentialError::Keyring(e.to_string()))
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Password, CredentialError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(CredentialError::Permissions(path.to_path_buf(), mode));
+        }
+    }
+
+    let mut contents = fs::read_to_string(path)?;
+    let password = Password(first_line(&contents).to_string());
+    contents.zeroize();
+    Ok(password)
+}
+
+fn run_command(cmd: &str) -> Result<Password, CredentialError> {
+    let mut output = Command::new("sh").arg("-c").arg(cmd).output()?;
+    if !output.status.success() {
+        output.stdout.zeroize();
+        return Err(CredentialError::Command(
+            cmd.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    let password = match std::str::from_utf8(&output.stdout) {
+        Ok(s) => Ok(Password(first_line(s).to_string())),
+        Err(_) => Err(CredentialError::Command(
+            cmd.to_string(),
+            "output is not valid UTF-8".into(),
+        )),
+    };
+    output.stdout.zeroize();
+    password
+}
+
+/// Only the first line is used, so files and commands may add trailing newlines or notes
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or("")
+}
+
+/// A password that is wiped from memory when dropped and never printed
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl Password {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Password {
+    fn from(s: String) -> Self {
+        Password(s)
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Password(<redacted>)")
+    }
+}
+
+#[derive(Debug)]
+pub enum CredentialError {
+    Missing,
+    Ambiguous,
+    Env(String),
+    Io(io::Error),
+    Permissions(PathBuf, u32),
+    Command(String, String),
+    Keyring(String),
+    OAuth2(String),
+    /// The server did not accept the credentials
+    Rejected(String),
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CredentialError::Missing => write!(f, "no password source configured"),
+            CredentialError::Ambiguous => write!(f, "more than one password source configured"),
+            CredentialError::Env(var) => write!(f, "environment variable {} is not set", var),
+            CredentialError::Io(e) => write!(f, "failed to read password: {}", e),
+            CredentialError::Permissions(path, mode) => write!(
+                f,
+                "password file {:?} is accessible by others (mode {:o}), use chmod 600",
+                path,
+                mode & 0o777
+            ),
+            CredentialError::Command(cmd, err) => {
+                write!(f, "password command {:?} failed: {}", cmd, err)
+            }
+            CredentialError::Keyring(e) => write!(f, "keyring error: {}", e),
+            CredentialError::OAuth2(e) => write!(f, "OAuth2 error: {}", e),
+            CredentialError::Rejected(msg) => write!(f, "login failed: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for CredentialError {
+    fn from(e: io::Error) -> Self {
+        CredentialError::Io(e)
+    }
+}
+
+const KEYRING_SERVICE: &str = "mailsync";
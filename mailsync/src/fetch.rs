@@ -0,0 +1,213 @@
+use std::io;
+use std::ops::RangeInclusive;
+
+use futures::future::ready;
+use futures::stream::TryStreamExt;
+use tokio_imap::builders::CommandBuilder;
+use tokio_imap::types::{Attribute, AttributeValue, Response};
+
+use crate::connection::Client;
+use crate::{SyncError, SYNC_FOLDER};
+
+/// A group of messages that can be fetched with a single command
+#[derive(Debug, PartialEq)]
+pub enum Batch {
+    /// All messages in this UID range, which together fit within the in-flight limit
+    Messages(RangeInclusive<u32>),
+    /// A single message exceeding the in-flight limit, to be fetched in chunks
+    Large { uid: u32, size: u32 },
+}
+
+impl Batch {
+    /// The highest UID in the batch
+    pub fn last_uid(&self) -> u32 {
+        match self {
+            Batch::Messages(uids) => *uids.end(),
+            Batch::Large { uid, .. } => *uid,
+        }
+    }
+}
+
+/// UIDs and sizes of the messages in the selected mailbox with a UID of at least `from`
+pub async fn sizes(client: &mut Client, from: u32) -> Result<Vec<(u32, u32)>, SyncError> {
+    let cmd = CommandBuilder::uid_fetch()
+        .range_from(from..)
+        .attr(Attribute::Uid)
+        .attr(Attribute::Rfc822Size);
+    let mut sizes = client
+        .call(cmd)
+        .try_fold(Vec::new(), |mut sizes, rd| {
+            if let Response::Fetch(_, attr_vals) = rd.parsed() {
+                let (mut uid, mut size) = (None, None);
+                for val in attr_vals.iter() {
+                    match *val {
+                        AttributeValue::Uid(u) => uid = Some(u),
+                        AttributeValue::Rfc822Size(s) => size = Some(s),
+                        _ => {}
+                    }
+                }
+                if let (Some(uid), Some(size)) = (uid, size) {
+                    sizes.push((uid, size));
+                }
+            }
+            ready(Ok(sizes))
+        })
+        .await?;
+
+    // `from:*` always includes the last message, even if its UID is lower than `from`
+    sizes.retain(|&(uid, _)| uid >= from);
+    sizes.sort_unstable();
+    Ok(sizes)
+}
+
+/// Split messages (as returned by `sizes()`) into batches of at most `max_in_flight` bytes
+pub fn batches(sizes: &[(u32, u32)], max_in_flight: usize) -> Vec<Batch> {
+    let mut batches = Vec::new();
+    let mut current: Option<(u32, u32)> = None;
+    let mut in_flight = 0;
+    for &(uid, size) in sizes {
+        let size_bytes = size as usize;
+        if size_bytes > max_in_flight || in_flight + size_bytes > max_in_flight {
+            if let Some((first, last)) = current.take() {
+                batches.push(Batch::Messages(first..=last));
+            }
+            in_flight = 0;
+        }
+
+        if size_bytes > max_in_flight {
+            batches.push(Batch::Large { uid, size });
+            continue;
+        }
+
+        current = Some(match current {
+            Some((first, _)) => (first, uid),
+            None => (uid, uid),
+        });
+        in_flight += size_bytes;
+    }
+
+    if let Some((first, last)) = current {
+        batches.push(Batch::Messages(first..=last));
+    }
+    batches
+}
+
+/// Fetch `len` bytes of a message's source, starting at `offset`
+///
+/// Uses `BODY.PEEK[]`, so that the message is not marked as seen.
+pub async fn chunk(
+    client: &mut Client,
+    uid: u32,
+    offset: u32,
+    len: u32,
+) -> Result<Vec<u8>, SyncError> {
+    let cmd = CommandBuilder::uid_fetch()
+        .num(uid)
+        .attr(Attribute::Uid)
+        .body_peek_partial(offset, len);
+    Ok(client
+        .call(cmd)
+        .try_fold(Vec::new(), |mut data, rd| {
+            if let Response::Fetch(_, attr_vals) = rd.parsed() {
+                for val in attr_vals.iter() {
+                    if let AttributeValue::BodySection {
+                        data: Some(bytes), ..
+                    } = *val
+                    {
+                        data.extend_from_slice(bytes);
+                    }
+                }
+            }
+            ready(Ok(data))
+        })
+        .await?)
+}
+
+/// Fetch the full source of a message, in chunks of at most `chunk_len` bytes
+pub async fn body(client: &mut Client, uid: u32, chunk_len: u32) -> Result<Vec<u8>, SyncError> {
+    if chunk_len == 0 {
+        let msg = "chunk length must not be zero";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+    }
+
+    let mut source = Vec::new();
+    loop {
+        let data = chunk(client, uid, source.len() as u32, chunk_len).await?;
+        let done = data.len() < chunk_len as usize;
+        source.extend_from_slice(&data);
+        if done {
+            return Ok(source);
+        }
+    }
+}
+
+/// Examine `SYNC_FOLDER`, so that stored messages can be fetched by their UID
+pub async fn examine_sync_folder(client: &mut Client) -> Result<(), SyncError> {
+    let _ = client
+        .call(CommandBuilder::examine(SYNC_FOLDER))
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(())
+}
+
+/// Download and store the body of a message that was synced with headers only
+///
+/// Expects `SYNC_FOLDER` to be selected. Returns the full message source, or `None` if
+/// the message is no longer on the server.
+pub async fn fill_body(
+    client: &mut Client,
+    db: &tokio_postgres::Client,
+    id: i32,
+    uid: u32,
+    chunk_len: u32,
+) -> Result<Option<Vec<u8>>, SyncError> {
+    let source = body(client, uid, chunk_len).await?;
+    if source.is_empty() {
+        return Ok(None);
+    }
+
+    // The headers are part of the source, so there's no need to keep them separately
+    db.execute(
+        "UPDATE messages SET bytes = $1, headers = NULL WHERE id = $2",
+        &[&source, &id],
+    )
+    .await?;
+    Ok(Some(source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_split_at_limit() {
+        let sizes = [(1, 10), (2, 10), (3, 10), (5, 5)];
+        assert_eq!(
+            batches(&sizes, 25),
+            vec![Batch::Messages(1..=2), Batch::Messages(3..=5)]
+        );
+        // A batch may fill the limit exactly
+        assert_eq!(
+            batches(&[(1, 25), (2, 1)], 25),
+            vec![Batch::Messages(1..=1), Batch::Messages(2..=2)]
+        );
+        assert_eq!(batches(&sizes, 100), vec![Batch::Messages(1..=5)]);
+        assert!(batches(&[], 25).is_empty());
+    }
+
+    #[test]
+    fn batches_with_large_messages() {
+        let sizes = [(1, 10), (2, 100), (3, 10), (4, 26), (7, 1)];
+        assert_eq!(
+            batches(&sizes, 25),
+            vec![
+                Batch::Messages(1..=1),
+                Batch::Large { uid: 2, size: 100 },
+                Batch::Messages(3..=3),
+                Batch::Large { uid: 4, size: 26 },
+                Batch::Messages(7..=7),
+            ]
+        );
+        assert_eq!(batches(&sizes, 25)[3].last_uid(), 4);
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset};
+use email_parser::Message;
+use postgres::types::ToSql;
+use postgres::{Client, Statement};
+
+use crate::Flag;
+
+/// Inserts messages read from local files, along with their labels
+pub struct Importer<'a> {
+    conn: &'a mut Client,
+    insert: Statement,
+    label: Statement,
+    link: Statement,
+    label_ids: HashMap<String, i32>,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(conn: &'a mut Client) -> Result<Self, postgres::Error> {
+        // Messages already stored by `sync` (or an earlier import) keep their row, so that
+        // the labels and thread ID are attached to it instead of failing on the unique index
+        let insert = conn.prepare(
+            "INSERT INTO messages (dt, subject, mid, bytes, thrid, flags, gm_msgid) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7) \
+             ON CONFLICT (gm_msgid) DO UPDATE SET \
+             thrid = COALESCE(EXCLUDED.thrid, messages.thrid), \
+             bytes = COALESCE(messages.bytes, EXCLUDED.bytes) \
+             RETURNING id",
+        )?;
+        let label = conn.prepare(
+            "INSERT INTO labels (name) VALUES ($1) \
+             ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+        )?;
+        let link = conn.prepare(
+            "INSERT INTO message_labels (message, label) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )?;
+
+        Ok(Self {
+            conn,
+            insert,
+            label,
+            link,
+            label_ids: HashMap::new(),
+        })
+    }
+
+    /// Insert a message, or add to the stored one with the same Gmail ID, returning its id
+    pub fn insert(&mut self, msg: &ImportMessage<'_>) -> Result<i32, postgres::Error> {
+        let parsed = Message::from_slice(msg.bytes);
+        let headers = parsed.headers();
+        let mid_raw = headers.get_first("message-id");
+        let message_id = match mid_raw {
+            Some(ref mid) => Some(mid.as_ref().trim()),
+            None => None as Option<&str>,
+        };
+
+        let subject_raw = headers.get_first("subject");
+        let subject = match subject_raw {
+            Some(ref subj) => Some(subj.as_ref().replace('\x00', "")),
+            None => None as Option<String>,
+        };
+
+        let thrid = msg.thread_id.map(|id| id as i64);
+        let gm_msgid = msg.gmail_id.map(|id| id as i64);
+        let params: [&(dyn ToSql + Sync); 7] = [
+            &msg.dt,
+            &subject,
+            &message_id,
+            &msg.bytes,
+            &thrid,
+            &msg.flags,
+            &gm_msgid,
+        ];
+        let id: i32 = self.conn.query_one(&self.insert, &params)?.get(0);
+        for label in msg.labels {
+            self.add_label(id, label)?;
+        }
+        Ok(id)
+    }
+
+    /// Add a label to a previously inserted message
+    pub fn add_label(&mut self, id: i32, label: &str) -> Result<(), postgres::Error> {
+        let label_id = self.label_id(label)?;
+        self.conn.execute(&self.link, &[&id, &label_id])?;
+        Ok(())
+    }
+
+    fn label_id(&mut self, name: &str) -> Result<i32, postgres::Error> {
+        if let Some(id) = self.label_ids.get(name) {
+            return Ok(*id);
+        }
+
+        let id = self.conn.query_one(&self.label, &[&name])?.get(0);
+        self.label_ids.insert(name.to_string(), id);
+        Ok(id)
+    }
+}
+
+pub struct ImportMessage<'a> {
+    pub dt: DateTime<FixedOffset>,
+    pub bytes: &'a [u8],
+    pub thread_id: Option<u64>,
+    /// Gmail's message ID (`X-GM-MSGID` in IMAP)
+    pub gmail_id: Option<u64>,
+    pub flags: &'a [Flag],
+    pub labels: &'a [String],
+}
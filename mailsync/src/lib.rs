@@ -1,115 +1,265 @@
-use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fs;
 use std::io;
+use std::net::SocketAddr;
 
 use chrono::{DateTime, FixedOffset};
+use futures::stream::TryStreamExt;
 use postgres_types::{FromSql, ToSql};
 use serde_derive::{Deserialize, Serialize};
-use tokio_imap::builders::{fetch, FetchCommand};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_imap::builders::{fetch, CommandBuilder, FetchCommand};
+use tokio_imap::types::{Attribute, AttributeValue, BodyStructure, Response, Status};
 use tokio_imap::ResponseData;
-use tokio_imap::types::{Attribute, AttributeValue, Response};
+use tracing::{debug, warn};
+
+use accumulate::{AttrSet, Collector, Fetched, Leftovers};
+use connection::{Capabilities, Security, Session, Stream, TlsConfig};
+use credentials::{Auth, CredentialError, Credentials, Password};
+use oauth2::OAuth2Config;
+
+pub mod accumulate;
+pub mod connection;
+pub mod credentials;
+pub mod fetch;
+pub mod import;
+pub mod logging;
+pub mod maildir;
+pub mod mbox;
+pub mod meta;
+pub mod metrics;
+pub mod oauth2;
+pub mod quarantine;
+pub mod reconcile;
+pub mod schema;
 
-#[derive(Default)]
 pub struct ResponseAccumulator {
-    parts: HashMap<u32, (u32, Vec<ResponseData>)>,
+    collector: Collector,
+    /// Only request MODSEQ from servers that support CONDSTORE
+    mod_seq: bool,
+    /// Request Gmail's message ID, thread ID and labels
+    gmail: bool,
+    /// Only request this many bytes of the message source, for large messages
+    first_chunk: Option<u32>,
+    /// Request headers, structure and size instead of the message source
+    headers_only: bool,
+    /// UIDs of messages that came in complete, but without a usable INTERNALDATE
+    invalid: Vec<u32>,
 }
 
 impl ResponseAccumulator {
-    pub fn new() -> ResponseAccumulator {
-        Self::default()
+    pub fn new(capabilities: &Capabilities) -> ResponseAccumulator {
+        let mut acc = Self {
+            collector: Collector::new(AttrSet::default()),
+            mod_seq: capabilities.condstore,
+            gmail: capabilities.gmail,
+            first_chunk: None,
+            headers_only: false,
+            invalid: Vec::new(),
+        };
+        acc.collector = Collector::new(acc.requested());
+        acc
+    }
+
+    /// Leave out message bodies, so they can be fetched later with `fetch::body()`
+    pub fn headers_only(mut self) -> Self {
+        self.headers_only = true;
+        self.collector = Collector::new(self.requested());
+        self
+    }
+
+    /// Fetch only the first `len` bytes of the source, so that the rest can be
+    /// fetched in chunks with `fetch::chunk()`
+    pub fn partial(mut self, len: u32) -> Self {
+        self.first_chunk = Some(len);
+        self
     }
 
     pub fn build_command_attributes(
+        &self,
         builder: FetchCommand<fetch::Messages>,
     ) -> FetchCommand<fetch::Attributes> {
-        builder
+        let mut builder = builder
             .attr(Attribute::Uid)
-            .attr(Attribute::ModSeq)
             .attr(Attribute::InternalDate)
-            .attr(Attribute::Rfc822)
-            .attr(Attribute::Flags)
+            .attr(Attribute::Flags);
+        builder = match (self.headers_only, self.first_chunk) {
+            // RFC822.HEADER is equivalent to BODY.PEEK[HEADER]
+            (true, _) => builder
+                .attr(Attribute::Rfc822Header)
+                .attr(Attribute::BodyStructure)
+                .attr(Attribute::Rfc822Size),
+            (false, Some(len)) => builder.body_peek_partial(0, len),
+            (false, None) => builder.attr(Attribute::Rfc822),
+        };
+        if self.mod_seq {
+            builder = builder.attr(Attribute::ModSeq);
+        }
+        if self.gmail {
+            builder = builder
+                .attr(Attribute::GmailMsgId)
+                .attr(Attribute::GmailThrId)
+                .attr(Attribute::GmailLabels);
+        }
+        builder
     }
 
-    pub fn push(mut self, rd: ResponseData) -> (Self, Option<MessageMeta>) {
-        use crate::AttributeValue::*;
+    /// The attributes requested by `build_command_attributes()`
+    fn requested(&self) -> AttrSet {
+        let body = match self.headers_only {
+            true => AttrSet::HEADER | AttrSet::STRUCTURE | AttrSet::SIZE,
+            false => AttrSet::SOURCE,
+        };
+        let mut attrs = AttrSet::UID | AttrSet::INTERNAL_DATE | AttrSet::FLAGS | body;
+        if self.mod_seq {
+            attrs = attrs | AttrSet::MOD_SEQ;
+        }
+        if self.gmail {
+            attrs = attrs | AttrSet::GMAIL;
+        }
+        attrs
+    }
 
-        let completed = {
-            let (_, entry) = match *rd.parsed() {
-                Response::Fetch(idx, ref attr_vals) => {
-                    let mut entry = self.parts.entry(idx).or_insert((0, vec![]));
-                    for val in attr_vals.iter() {
-                        entry.0 += match *val {
-                            Uid(_) | ModSeq(_) | InternalDate(_) | Flags(_) | Rfc822(_) => 1,
-                            _ => 0,
-                        };
-                    }
-                    (idx, entry)
+    pub fn push(mut self, rd: ResponseData) -> (Self, Option<Fetched<MessageMeta>>) {
+        let fetched = match self.collector.push(rd) {
+            Some(Fetched::Message(rds)) => match Self::extract(rds) {
+                Ok(meta) => Some(Fetched::Message(meta)),
+                Err(uid) => {
+                    self.invalid.push(uid);
+                    None
                 }
-                _ => return (self, None),
-            };
-            entry.1.push(rd);
-
-            // If we think all the parts are in, extract the relevant content
-            // into a single structure
-            if entry.0 == 5 {
-                let mut seq = None;
-                let mut uid = None;
-                let mut mod_seq = None;
-                let mut dt = None;
-                let mut flags = Vec::new();
-                let mut source = None;
-                for rd in entry.1.drain(..) {
-                    if let Response::Fetch(rsp_seq, attr_vals) = rd.parsed() {
-                        seq = Some(*rsp_seq);
-                        for val in attr_vals.iter() {
-                            match *val {
-                                Uid(u) => {
-                                    uid = Some(u);
-                                }
-                                ModSeq(ms) => {
-                                    mod_seq = Some(ms);
-                                }
-                                InternalDate(id) => {
-                                    let parsed =
-                                        DateTime::parse_from_str(id, "%d-%b-%Y %H:%M:%S %z");
-                                    dt = Some(parsed.unwrap());
-                                }
-                                Flags(ref fs) => {
-                                    flags.extend(fs.iter().filter_map(|&f| Flag::from_str(f)));
-                                }
-                                Rfc822(Some(src)) => {
-                                    source = Some(src.to_vec());
+            },
+            Some(Fetched::Flags(update)) => Some(Fetched::Flags(update)),
+            None => None,
+        };
+        (self, fetched)
+    }
+
+    /// Report what's left once the command is done, see `Collector::finish()`
+    ///
+    /// Messages without a usable INTERNALDATE count as incomplete.
+    pub fn finish(self) -> Leftovers {
+        let mut leftovers = self.collector.finish();
+        leftovers.incomplete.extend(self.invalid);
+        leftovers.incomplete.sort_unstable();
+        leftovers
+    }
+
+    /// Combine the responses for a complete message into a single structure
+    ///
+    /// Returns the UID instead if the INTERNALDATE is missing or can't be parsed.
+    fn extract(rds: Vec<ResponseData>) -> Result<MessageMeta, u32> {
+        use crate::AttributeValue::*;
+
+        let mut seq = None;
+        let mut uid = None;
+        let mut mod_seq = None;
+        let mut dt = None;
+        let mut flags = Vec::new();
+        let mut source = None;
+        let mut headers = None;
+        let mut size = None;
+        let mut parts = Vec::new();
+        let mut gm_msgid = None;
+        let mut gm_thrid = None;
+        let mut labels = Vec::new();
+        for rd in rds {
+            if let Response::Fetch(rsp_seq, attr_vals) = rd.parsed() {
+                seq = Some(*rsp_seq);
+                for val in attr_vals.iter() {
+                    match *val {
+                        Uid(u) => {
+                            uid = Some(u);
+                        }
+                        ModSeq(ms) => {
+                            mod_seq = Some(ms);
+                        }
+                        InternalDate(id) => {
+                            match DateTime::parse_from_str(id, "%d-%b-%Y %H:%M:%S %z") {
+                                Ok(parsed) => dt = Some(parsed),
+                                Err(e) => {
+                                    warn!(internal_date = id, error = %e, "invalid INTERNALDATE")
                                 }
-                                _ => {}
                             }
                         }
-                    };
+                        Flags(ref fs) => {
+                            // Later responses may carry updated flags
+                            flags.clear();
+                            flags.extend(fs.iter().filter_map(|&f| Flag::from_str(f)));
+                        }
+                        Rfc822(Some(src)) => {
+                            source = Some(src.to_vec());
+                        }
+                        BodySection {
+                            data: Some(src), ..
+                        } => {
+                            source = Some(src.to_vec());
+                        }
+                        Rfc822Header(Some(src)) => {
+                            headers = Some(src.to_vec());
+                        }
+                        BodyStructure(ref body) => {
+                            content_types(body, &mut parts);
+                        }
+                        Rfc822Size(s) => {
+                            size = Some(s);
+                        }
+                        GmailMsgId(id) => {
+                            gm_msgid = Some(id);
+                        }
+                        GmailThrId(id) => {
+                            gm_thrid = Some(id);
+                        }
+                        GmailLabels(ref ls) => {
+                            labels.extend(ls.iter().map(|l| l.to_string()));
+                        }
+                        _ => {}
+                    }
                 }
-                Some(MessageMeta {
-                    seq: seq.unwrap(),
-                    uid: uid.unwrap(),
-                    mod_seq: mod_seq.unwrap(),
-                    dt: dt.unwrap(),
-                    flags,
-                    raw: source.unwrap(),
-                })
-            } else {
-                None
-            }
+            };
+        }
+        // The collector only completes messages once their UID came in
+        let uid = uid.unwrap();
+        let dt = match dt {
+            Some(dt) => dt,
+            None => return Err(uid),
         };
+        Ok(MessageMeta {
+            seq: seq.unwrap(),
+            uid,
+            mod_seq: mod_seq.unwrap_or(0),
+            dt,
+            flags,
+            gm_msgid,
+            gm_thrid,
+            labels,
+            raw: source,
+            headers,
+            size,
+            parts,
+        })
+    }
+}
 
-        // Once we have found a complete Meta struct, remove the related messages
-        // from the internal parts cache and return the Meta struct.
-        if let Some(ref meta) = completed {
-            self.parts.remove(&meta.seq);
+/// Collect the content types of all leaf parts in a BODYSTRUCTURE
+fn content_types(body: &BodyStructure, types: &mut Vec<String>) {
+    let common = match body {
+        BodyStructure::Multipart { bodies, .. } => {
+            for body in bodies {
+                content_types(body, types);
+            }
+            return;
         }
-        (self, completed)
-    }
+        BodyStructure::Basic { common, .. }
+        | BodyStructure::Text { common, .. }
+        | BodyStructure::Message { common, .. } => common,
+    };
+    types.push(format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase());
 }
 
 pub fn fuzzy_datetime_parser(orig: &str) -> Option<DateTime<FixedOffset>> {
     let mut s = orig.to_string();
-    if &s[3..5] == ", " {
+    if s.get(3..5) == Some(", ") {
         s = s[5..].trim().to_string();
     } else if s.starts_with("Wed ") || s.starts_with("Sun ") || s.starts_with("Sat ") {
         s = s[4..].trim().to_string();
@@ -195,13 +345,36 @@ const FORMATS: [&str; 5] = [
 pub struct MessageMeta {
     pub seq: u32,
     pub uid: u32,
+    /// Zero if the server doesn't support CONDSTORE
     pub mod_seq: u64,
     pub dt: DateTime<FixedOffset>,
     pub flags: Vec<Flag>,
-    pub raw: Vec<u8>,
+    /// Gmail's message ID, which is stable across mailboxes and Takeout exports
+    pub gm_msgid: Option<u64>,
+    pub gm_thrid: Option<u64>,
+    /// Gmail labels, with system labels like `\Inbox`
+    pub labels: Vec<String>,
+    /// The full message source, absent when only headers were fetched
+    pub raw: Option<Vec<u8>>,
+    /// The header section, only fetched instead of `raw`
+    pub headers: Option<Vec<u8>>,
+    /// Size of the full message, if only headers were fetched
+    pub size: Option<u32>,
+    /// Content types of the message's leaf parts, if only headers were fetched
+    pub parts: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, FromSql, PartialEq, Serialize, ToSql)]
+impl MessageMeta {
+    /// The message source if available, or else its header section
+    pub fn header_source(&self) -> &[u8] {
+        match self.raw.as_ref().or_else(|| self.headers.as_ref()) {
+            Some(source) => source,
+            None => &[],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, FromSql, PartialEq, Serialize, ToSql)]
 #[postgres(name = "flags")]
 pub enum Flag {
     #[postgres(name = "\\Answered")]
@@ -220,31 +393,103 @@ impl Flag {
             "\\Seen" => Some(Flag::Seen),
             "Junk" | "$Phishing" | "NonJunk" | "$MDNSent" | "$Forwarded" => None,
             v => {
-                println!("unknown flag: {}", v);
+                tracing::warn!(flag = v, "unknown flag");
                 None
             }
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Flag::Answered => "\\Answered",
+            Flag::Flagged => "\\Flagged",
+            Flag::Seen => "\\Seen",
+        }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Config {
     pub imap: ImapConfig,
     pub store: StoreConfig,
+    #[serde(default)]
+    pub web: WebConfig,
 }
 
 impl Config {
     pub fn from_file(name: &str) -> Self {
         let s = fs::read_to_string(name).unwrap();
-        toml::from_str(&s).unwrap()
+        let config: Config = toml::from_str(&s).unwrap();
+        // Chunks of large messages are fetched with a partial FETCH of this many bytes
+        let max_in_flight = config.imap.max_in_flight;
+        assert!(
+            max_in_flight > 0 && u32::try_from(max_in_flight).is_ok(),
+            "imap.max_in_flight must be between 1 and {}",
+            u32::MAX
+        );
+        config
     }
 }
 
 #[derive(Deserialize)]
 pub struct ImapConfig {
     pub server: String,
+    /// Defaults to 993 for implicit TLS and 143 otherwise
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: Security,
+    #[serde(default)]
+    pub tls: TlsConfig,
     pub account: String,
-    pub password: String,
+    #[serde(flatten)]
+    pub credentials: Credentials,
+    /// Log in with OAuth2 instead of a password, using the password as refresh token
+    pub oauth2: Option<OAuth2Config>,
+    /// Maximum number of bytes of message sources to fetch with a single command
+    ///
+    /// Larger messages are fetched in chunks of this size. With several connections,
+    /// `sync` divides this between them. Fetched messages that wait to be stored are
+    /// limited by number rather than size, so memory use can go above this.
+    #[serde(default = "ImapConfig::default_max_in_flight")]
+    pub max_in_flight: usize,
+    /// Maximum number of connections `sync` opens at once
+    ///
+    /// Gmail allows 15 per account, shared with all other clients.
+    #[serde(default = "ImapConfig::default_max_connections")]
+    pub max_connections: usize,
+    /// Use Gmail's X-GM-MSGID, X-GM-THRID and X-GM-LABELS if the server supports them
+    #[serde(default = "ImapConfig::default_gmail")]
+    pub gmail: bool,
+    /// Mailbox that archived INBOX messages are moved to
+    #[serde(default = "ImapConfig::default_archive")]
+    pub archive: String,
+}
+
+impl ImapConfig {
+    /// Resolve the configured credentials, running commands and reading files as needed
+    pub fn auth(&self) -> Result<Auth, CredentialError> {
+        Auth::new(&self.credentials, self.oauth2.as_ref(), &self.account)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.security.default_port())
+    }
+
+    fn default_archive() -> String {
+        "[Gmail]/All Mail".into()
+    }
+
+    fn default_gmail() -> bool {
+        true
+    }
+
+    fn default_max_in_flight() -> usize {
+        32 * 1024 * 1024
+    }
+
+    fn default_max_connections() -> usize {
+        4
+    }
 }
 
 #[derive(Deserialize)]
@@ -252,8 +497,225 @@ pub struct StoreConfig {
     pub uri: String,
 }
 
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    pub listen: SocketAddr,
+    pub page_size: usize,
+    /// Base64-encoded key for signing session cookies (random per run if absent)
+    pub session_key: Option<String>,
+    /// Only send session cookies over HTTPS; enable when behind a TLS-terminating proxy
+    pub secure_cookies: bool,
+    pub users: Vec<UserConfig>,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self {
+            listen: "[::]:3000".parse().unwrap(),
+            page_size: 100,
+            session_key: None,
+            secure_cookies: false,
+            users: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub password_hash: String,
+    /// Accounts this user may see; all archived accounts if absent
+    pub accounts: Option<Vec<String>>,
+}
+
+impl UserConfig {
+    pub fn can_access(&self, account: &str) -> bool {
+        match &self.accounts {
+            Some(accounts) => accounts.iter().any(|a| a == account),
+            None => true,
+        }
+    }
+}
+
+/// The mailbox that `sync` archives, in which the `unid` of stored messages is valid
+pub const SYNC_FOLDER: &str = "[Gmail]/All Mail";
+
+/// Connect to the configured IMAP server and log in
+///
+/// The credentials are passed in separately, so that they only have to be resolved once.
+/// The server's capabilities are requested after logging in, since they may differ
+/// from what was announced to unauthenticated clients.
+pub async fn login(config: &ImapConfig, auth: &Auth) -> Result<Session, SyncError> {
+    let stream = connection::connect(config).await?;
+    let mut client = authenticate(stream, config, auth).await?;
+    let mut capabilities = Capabilities::fetch(&mut client).await?;
+    capabilities.gmail &= config.gmail;
+    debug!(?capabilities, "logged in");
+    Ok(Session {
+        client,
+        capabilities,
+    })
+}
+
+/// Log in on a freshly connected stream, returning the client to use from then on
+///
+/// With OAuth2, a rejected access token is refreshed once before giving up.
+async fn authenticate(
+    mut stream: Stream,
+    config: &ImapConfig,
+    auth: &Auth,
+) -> Result<connection::Client, SyncError> {
+    let session = match auth {
+        Auth::Password(password) => {
+            let mut client = tokio_imap::Client::new(stream);
+            let cmd = CommandBuilder::login(&config.account, password.expose());
+            authenticated(&mut client, cmd).await?;
+            return Ok(client);
+        }
+        Auth::OAuth2(session) => session,
+    };
+
+    let mechanism = session.mechanism().as_str();
+    let capabilities = raw_capabilities(&mut stream).await?;
+    if !capabilities.contains(&format!("AUTH={}", mechanism)) {
+        let msg = format!("server doesn't support AUTH={}", mechanism);
+        return Err(CredentialError::OAuth2(msg).into());
+    }
+    let inline = capabilities.iter().any(|name| name == "SASL-IR");
+
+    let mut attempt = 0;
+    loop {
+        let token = session.access_token().await?;
+        let response =
+            session.initial_response(&config.account, &config.server, config.port(), &token);
+        let tag = format!("auth{}", attempt);
+        match sasl(&mut stream, &tag, mechanism, &response, inline).await {
+            Ok(()) => return Ok(tokio_imap::Client::new(stream)),
+            Err(SyncError::Credentials(CredentialError::Rejected(_))) if attempt == 0 => {
+                session.invalidate();
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Ask for the capabilities on the raw stream, before logging in
+///
+/// Returns the capability names in upper case.
+async fn raw_capabilities<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<Vec<String>, SyncError> {
+    stream.write_all(b"cap0 CAPABILITY\r\n").await?;
+    let mut names = Vec::new();
+    loop {
+        let line = connection::read_line(stream).await?;
+        let line = line.trim_end();
+        if let Some(rest) = line.strip_prefix("* CAPABILITY ") {
+            names.extend(
+                rest.split_whitespace()
+                    .map(|name| name.to_ascii_uppercase()),
+            );
+        } else if let Some(rest) = line.strip_prefix("cap0 ") {
+            return match rest.starts_with("OK") {
+                true => Ok(names),
+                false => {
+                    let msg = format!("unexpected server response: {}", line);
+                    Err(io::Error::new(io::ErrorKind::InvalidData, msg).into())
+                }
+            };
+        }
+    }
+}
+
+/// Run `AUTHENTICATE` on the raw stream
+///
+/// With `inline`, the response is sent along with the command (SASL-IR, RFC 4959);
+/// otherwise it answers the server's first continuation request. A server rejecting an
+/// OAuth2 token then sends another continuation request with error details, and only
+/// sends the tagged NO after the client answers it with an empty line.
+async fn sasl<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    tag: &str,
+    mechanism: &str,
+    response: &Password,
+    inline: bool,
+) -> Result<(), SyncError> {
+    let encoded = Password::from(base64::encode(response.expose()));
+    let mut pending = match inline {
+        true => {
+            let command = Password::from(format!(
+                "{} AUTHENTICATE {} {}\r\n",
+                tag,
+                mechanism,
+                encoded.expose()
+            ));
+            stream.write_all(command.expose().as_bytes()).await?;
+            None
+        }
+        false => {
+            let command = format!("{} AUTHENTICATE {}\r\n", tag, mechanism);
+            stream.write_all(command.as_bytes()).await?;
+            Some(encoded)
+        }
+    };
+
+    loop {
+        let line = connection::read_line(stream).await?;
+        let line = line.trim_end();
+        if let Some(challenge) = line.strip_prefix("+") {
+            if let Some(encoded) = pending.take() {
+                let answer = Password::from(format!("{}\r\n", encoded.expose()));
+                stream.write_all(answer.expose().as_bytes()).await?;
+                continue;
+            }
+
+            let details = base64::decode(challenge.trim()).unwrap_or_default();
+            debug!(details = %String::from_utf8_lossy(&details), "authentication failed");
+            stream.write_all(b"\r\n").await?;
+            continue;
+        }
+
+        let rest = match line.strip_prefix(tag) {
+            Some(rest) if rest.starts_with(' ') => rest.trim_start(),
+            // Untagged responses, like capabilities sent along with the OK
+            _ => continue,
+        };
+        return match rest.starts_with("OK") {
+            true => Ok(()),
+            false => {
+                let msg = rest.splitn(2, ' ').nth(1).unwrap_or("no reason given");
+                Err(CredentialError::Rejected(msg.to_string()).into())
+            }
+        };
+    }
+}
+
+/// Run a login command, checking that the server accepted it
+async fn authenticated(
+    client: &mut connection::Client,
+    cmd: tokio_imap::proto::Command,
+) -> Result<(), SyncError> {
+    let responses = client.call(cmd).try_collect::<Vec<_>>().await?;
+    for rd in &responses {
+        if let Response::Done {
+            status,
+            information,
+            ..
+        } = rd.parsed()
+        {
+            if *status != Status::Ok {
+                let msg = information.unwrap_or("no reason given").to_string();
+                return Err(CredentialError::Rejected(msg).into());
+            }
+        }
+    }
+    Ok(())
+}
+
 pub struct Context {
-    pub client: tokio_imap::TlsClient,
+    pub client: connection::Client,
     pub db: tokio_postgres::Client,
 }
 
@@ -284,4 +746,230 @@ macro_rules! error_enum {
     };
 }
 
-error_enum!(SyncError, Io: io::Error, Pg: tokio_postgres::error::Error,);
+error_enum!(
+    SyncError,
+    Io: io::Error,
+    Pg: tokio_postgres::error::Error,
+    Csv: csv::Error,
+    Credentials: credentials::CredentialError,
+);
+
+impl SyncError {
+    /// Short name for the kind of error, as used in metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyncError::Io(_) => "io",
+            SyncError::Pg(_) => "postgres",
+            SyncError::Csv(_) => "csv",
+            SyncError::Credentials(_) => "credentials",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    use bytes::BytesMut;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response as HttpResponse, Server};
+    use tokio::net::TcpListener;
+    use tokio_imap::proto::ImapCodec;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
+
+    #[test]
+    fn invalid_internal_date() {
+        let mut buf = BytesMut::from(
+            &b"* 1 FETCH (UID 7 FLAGS () INTERNALDATE \"yesterday\" RFC822 {2}\r\nhi)\r\n"[..],
+        );
+        let rd = ImapCodec::default().decode(&mut buf).unwrap().unwrap();
+        let (acc, fetched) = ResponseAccumulator::new(&Capabilities::default()).push(rd);
+        assert!(fetched.is_none());
+        assert_eq!(acc.finish().incomplete, vec![7]);
+    }
+
+    #[tokio::test]
+    async fn oauth2_success() {
+        let cache = token_cache("success", "cached");
+        let (addr, server) = mock_imap(&[CAPABILITIES, "auth0 OK Success\r\n"]).await;
+        let (config, auth) = oauth2_config(addr, "http://127.0.0.1:1/token", &cache);
+
+        let stream = connection::connect(&config).await.unwrap();
+        authenticate(stream, &config, &auth).await.unwrap();
+        let lines = server.await.unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "cap0 CAPABILITY\r\n");
+        assert_eq!(bearer(&lines[1], "auth0"), "cached");
+        let _ = fs::remove_file(&cache);
+    }
+
+    #[tokio::test]
+    async fn oauth2_without_initial_response() {
+        let cache = token_cache("no-sasl-ir", "cached");
+        let (addr, server) = mock_imap(&[
+            "* CAPABILITY IMAP4rev1 AUTH=XOAUTH2\r\ncap0 OK done\r\n",
+            "+ \r\n",
+            "auth0 OK Success\r\n",
+        ])
+        .await;
+        let (config, auth) = oauth2_config(addr, "http://127.0.0.1:1/token", &cache);
+
+        let stream = connection::connect(&config).await.unwrap();
+        authenticate(stream, &config, &auth).await.unwrap();
+        let lines = server.await.unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "auth0 AUTHENTICATE XOAUTH2\r\n");
+        assert_eq!(token(lines[2].trim_end()), "cached");
+        let _ = fs::remove_file(&cache);
+    }
+
+    #[tokio::test]
+    async fn oauth2_refresh_after_rejection() {
+        let cache = token_cache("refresh", "stale");
+        let token_url = mock_token_endpoint("fresh");
+        let (addr, server) = mock_imap(&[
+            CAPABILITIES,
+            "+ eyJzdGF0dXMiOiI0MDEiLCJzY2hlbWVzIjoiQmVhcmVyIn0=\r\n",
+            "auth0 NO [AUTHENTICATIONFAILED] Invalid credentials (Failure)\r\n",
+            "* CAPABILITY IMAP4rev1\r\nauth1 OK Success\r\n",
+        ])
+        .await;
+        let (config, auth) = oauth2_config(addr, &token_url, &cache);
+
+        let stream = connection::connect(&config).await.unwrap();
+        authenticate(stream, &config, &auth).await.unwrap();
+        let lines = server.await.unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(bearer(&lines[1], "auth0"), "stale");
+        assert_eq!(lines[2], "\r\n");
+        assert_eq!(bearer(&lines[3], "auth1"), "fresh");
+        let _ = fs::remove_file(&cache);
+    }
+
+    #[tokio::test]
+    async fn oauth2_rejected_with_continuation() {
+        let cache = token_cache("rejected", "stale");
+        let token_url = mock_token_endpoint("revoked");
+        let (addr, server) = mock_imap(&[
+            CAPABILITIES,
+            "+ eyJzdGF0dXMiOiI0MDEifQ==\r\n",
+            "auth0 NO [AUTHENTICATIONFAILED] Invalid credentials (Failure)\r\n",
+            "+ eyJzdGF0dXMiOiI0MDEifQ==\r\n",
+            "auth1 NO [AUTHENTICATIONFAILED] Invalid credentials (Failure)\r\n",
+        ])
+        .await;
+        let (config, auth) = oauth2_config(addr, &token_url, &cache);
+
+        let stream = connection::connect(&config).await.unwrap();
+        match authenticate(stream, &config, &auth).await {
+            Err(SyncError::Credentials(CredentialError::Rejected(msg))) => {
+                assert_eq!(msg, "[AUTHENTICATIONFAILED] Invalid credentials (Failure)")
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("login should fail"),
+        }
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(bearer(&lines[3], "auth1"), "revoked");
+        assert_eq!(lines[4], "\r\n");
+        let _ = fs::remove_file(&cache);
+    }
+
+    /// Reply to the pre-login `CAPABILITY` command, for a server that supports SASL-IR
+    const CAPABILITIES: &str = "* CAPABILITY IMAP4rev1 SASL-IR AUTH=XOAUTH2\r\ncap0 OK done\r\n";
+
+    /// Serve a single connection, answering each line from the client with the next reply
+    ///
+    /// The returned task resolves to the lines the client sent.
+    async fn mock_imap(
+        replies: &'static [&'static str],
+    ) -> (SocketAddr, tokio::task::JoinHandle<Vec<String>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"* OK mock ready\r\n").await.unwrap();
+            let mut lines = Vec::new();
+            for reply in replies {
+                lines.push(connection::read_line(&mut stream).await.unwrap());
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+            lines
+        });
+        (addr, server)
+    }
+
+    /// Serve a token endpoint that hands out the given access token
+    fn mock_token_endpoint(token: &'static str) -> String {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                let body = format!(r#"{{"access_token":"{}","expires_in":3600}}"#, token);
+                Ok::<_, Infallible>(HttpResponse::new(Body::from(body)))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/token", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn oauth2_config(addr: SocketAddr, token_url: &str, cache: &PathBuf) -> (ImapConfig, Auth) {
+        let config: ImapConfig = toml::from_str(&format!(
+            r#"
+            server = "127.0.0.1"
+            port = {}
+            security = "plain"
+            account = "user@example.com"
+            password = "refresh-token"
+
+            [oauth2]
+            token_url = "{}"
+            client_id = "client"
+            token_cache = "{}"
+            "#,
+            addr.port(),
+            token_url,
+            cache.display()
+        ))
+        .unwrap();
+        let auth = config.auth().unwrap();
+        (config, auth)
+    }
+
+    /// Write a cached, unexpired access token, so that the first attempt doesn't refresh
+    fn token_cache(name: &str, token: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mailsync-test-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        let expires_at = chrono::Utc::now().timestamp() + 3600;
+        let data = format!(
+            r#"{{"access_token":"{}","expires_at":{}}}"#,
+            token, expires_at
+        );
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    /// The bearer token in an `AUTHENTICATE XOAUTH2` command line
+    fn bearer(line: &str, tag: &str) -> String {
+        let prefix = format!("{} AUTHENTICATE XOAUTH2 ", tag);
+        token(line.trim_end().strip_prefix(prefix.as_str()).unwrap())
+    }
+
+    /// The bearer token in a base64-encoded XOAUTH2 response
+    fn token(encoded: &str) -> String {
+        let response = String::from_utf8(base64::decode(encoded).unwrap()).unwrap();
+        let auth = response
+            .split('\x01')
+            .find(|s| s.starts_with("auth="))
+            .unwrap();
+        auth["auth=Bearer ".len()..].to_string()
+    }
+}
@@ -0,0 +1,52 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing_subscriber::EnvFilter;
+
+/// Log to stderr, unless overridden through `RUST_LOG`
+///
+/// Logs at `info` level by default, `debug` with a verbosity of 1 and `trace` above that.
+pub fn init(verbosity: u8) {
+    let level = match verbosity {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Progress bar counting items, with rate and ETA
+///
+/// Shows a spinner if the total is not known up front. Like all progress bars here,
+/// it is hidden if stderr is not a terminal.
+pub fn progress(total: Option<u64>, unit: &str) -> ProgressBar {
+    let (bar, template) = match total {
+        Some(len) => (
+            ProgressBar::new(len),
+            format!(
+                "{{elapsed_precise}} [{{wide_bar}}] {{pos}}/{{len}} {} ({{per_sec}}, ETA {{eta}})",
+                unit
+            ),
+        ),
+        None => (
+            ProgressBar::new_spinner(),
+            format!(
+                "{{elapsed_precise}} {{spinner}} {{pos}} {} ({{per_sec}})",
+                unit
+            ),
+        ),
+    };
+    bar.set_style(ProgressStyle::default_bar().template(&template));
+    bar
+}
+
+/// Progress bar for working through `total` bytes of input
+pub fn progress_bytes(total: u64) -> ProgressBar {
+    let bar = ProgressBar::new(total);
+    bar.set_style(ProgressStyle::default_bar().template(
+        "{elapsed_precise} [{wide_bar}] {bytes}/{total_bytes} ({bytes_per_sec}, ETA {eta})",
+    ));
+    bar
+}
@@ -0,0 +1,177 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::{DateTime, FixedOffset, Utc};
+
+use crate::Flag;
+
+/// A single Maildir folder (`cur`, `new` and `tmp` subdirectories)
+pub struct Maildir {
+    path: PathBuf,
+}
+
+impl Maildir {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Create the folder's directory structure if it does not exist yet
+    pub fn create(&self) -> io::Result<()> {
+        for sub in &["cur", "new", "tmp"] {
+            fs::create_dir_all(self.path.join(sub))?;
+        }
+        Ok(())
+    }
+
+    /// List the Maildir++ subfolders (`.Name` directories), with matching labels
+    pub fn subfolders(&self) -> io::Result<Vec<(String, Maildir)>> {
+        let mut folders = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') || name == "." || name == ".." {
+                continue;
+            }
+
+            if entry.file_type()?.is_dir() {
+                folders.push((folder_label(&name[1..]), Maildir::new(entry.path())));
+            }
+        }
+        folders.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(folders)
+    }
+
+    /// List the messages in `new` and `cur`, with the flags from their file names
+    pub fn entries(&self) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for sub in &["new", "cur"] {
+            let dir = self.path.join(sub);
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for item in fs::read_dir(&dir)? {
+                let item = item?;
+                if !item.file_type()?.is_file() {
+                    continue;
+                }
+
+                let name = item.file_name().to_string_lossy().into_owned();
+                let flags = match name.rfind(INFO_SEPARATOR) {
+                    Some(i) => flags_from_info(&name[i + 1..]),
+                    None => Vec::new(),
+                };
+
+                entries.push(Entry {
+                    path: item.path(),
+                    flags,
+                    mtime: item.metadata()?.modified().ok(),
+                });
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// Deliver a message straight into `cur`, with flags encoded in the file name
+    ///
+    /// The message is written to `tmp` first, so readers never see partial files. The
+    /// unique part of the name comes from the message's `id` in the store, so exporting
+    /// the same message again replaces the file instead of adding another copy.
+    pub fn store(
+        &self,
+        id: i32,
+        dt: &DateTime<FixedOffset>,
+        flags: &[Flag],
+        bytes: &[u8],
+    ) -> io::Result<PathBuf> {
+        let unique = format!("{}.M{}.mailsync", dt.timestamp(), id);
+        let tmp = self.path.join("tmp").join(&unique);
+        fs::write(&tmp, bytes)?;
+
+        let name = format!("{}{}2,{}", unique, INFO_SEPARATOR, info_from_flags(flags));
+        let path = self.path.join("cur").join(name);
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+}
+
+pub struct Entry {
+    pub path: PathBuf,
+    pub flags: Vec<Flag>,
+    pub mtime: Option<SystemTime>,
+}
+
+impl Entry {
+    /// The file's modification time, as a fallback for messages without a usable date
+    pub fn modified(&self) -> Option<DateTime<FixedOffset>> {
+        let dt: DateTime<Utc> = self.mtime?.into();
+        Some(dt.into())
+    }
+}
+
+/// Parse the info suffix of a file name (the part after the colon, `2,FRS`)
+pub fn flags_from_info(info: &str) -> Vec<Flag> {
+    let flags = match info.strip_prefix("2,") {
+        Some(flags) => flags,
+        None => return Vec::new(),
+    };
+
+    flags
+        .chars()
+        .filter_map(|c| match c {
+            'F' => Some(Flag::Flagged),
+            'R' => Some(Flag::Answered),
+            'S' => Some(Flag::Seen),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Build the flag characters for the info suffix, in ASCII order as required
+pub fn info_from_flags(flags: &[Flag]) -> String {
+    let mut info = flags
+        .iter()
+        .map(|f| match f {
+            Flag::Flagged => 'F',
+            Flag::Answered => 'R',
+            Flag::Seen => 'S',
+        })
+        .collect::<Vec<_>>();
+    info.sort();
+    info.dedup();
+    info.into_iter().collect()
+}
+
+/// Map a label to a Maildir++ folder name below `root`
+///
+/// Messages labeled `\Inbox` go into the root folder itself; other system labels
+/// lose their backslash and nested labels become dot-separated.
+pub fn label_folder(root: &Path, label: &str) -> PathBuf {
+    if label == "\\Inbox" {
+        return root.to_path_buf();
+    }
+
+    let name = label
+        .trim_start_matches('\\')
+        .replace('.', "_")
+        .replace('/', ".");
+    root.join(format!(".{}", name))
+}
+
+/// Inverse of `label_folder()` for a subfolder name (without the leading dot)
+fn folder_label(name: &str) -> String {
+    if SYSTEM_LABELS.contains(&name) {
+        return format!("\\{}", name);
+    }
+    name.replace('.', "/")
+}
+
+const SYSTEM_LABELS: [&str; 6] = ["Draft", "Important", "Sent", "Spam", "Starred", "Trash"];
+const INFO_SEPARATOR: char = ':';
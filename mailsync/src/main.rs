@@ -0,0 +1,84 @@
+use std::fmt::Debug;
+use std::process;
+
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+use tracing::error;
+
+use mailsync::{logging, Config};
+
+mod cmd;
+
+fn main() {
+    let options = Options::from_args();
+    logging::init(options.verbose);
+    let config = Config::from_file(&options.config);
+    let dry_run = options.dry_run;
+
+    match options.command {
+        Command::Sync(opts) => Runtime::new()
+            .unwrap()
+            .block_on(cmd::sync::run(config, dry_run, opts)),
+        Command::GetMeta(opts) => Runtime::new()
+            .unwrap()
+            .block_on(cmd::get_meta::run(config, dry_run, opts)),
+        Command::FillBodies(opts) => Runtime::new()
+            .unwrap()
+            .block_on(cmd::fill_bodies::run(config, dry_run, opts)),
+        Command::ImportMbox(opts) => cmd::import::mbox(&config, dry_run, opts),
+        Command::ImportMaildir(opts) => cmd::import::maildir(&config, dry_run, opts),
+        Command::Reconcile(opts) => cmd::reconcile::run(&config, dry_run, opts),
+        Command::Quarantine(opts) => cmd::quarantine::run(&config, dry_run, opts),
+        Command::Export(opts) => cmd::export::run(&config, dry_run, opts),
+        Command::Migrate => cmd::migrate::run(&config, dry_run),
+        Command::Verify(opts) => cmd::verify::run(&config, opts),
+    }
+}
+
+/// Log the error a command failed with, and exit with status 1
+fn exit_on_error<E: Debug>(command: &str, result: Result<(), E>) {
+    if let Err(e) = result {
+        error!(error = ?e, "{} failed", command);
+        process::exit(1);
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "mailsync")]
+struct Options {
+    /// Path to the configuration file
+    #[structopt(short, long, default_value = "mailsync.toml", global = true)]
+    config: String,
+    /// Log more details (repeat for even more)
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+    /// Report what would be done, without changing the archive
+    #[structopt(long, global = true)]
+    dry_run: bool,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Fetch new messages from the server into the archive
+    Sync(cmd::sync::Options),
+    /// Push local changes and update INBOX metadata in the database
+    GetMeta(cmd::get_meta::Options),
+    /// Download bodies of messages fetched with `sync --headers-only`
+    FillBodies(cmd::fill_bodies::Options),
+    /// Import messages from an mbox file
+    ImportMbox(cmd::import::MboxOptions),
+    /// Import messages from a Maildir++ hierarchy
+    ImportMaildir(cmd::import::MaildirOptions),
+    /// Match archived messages without UID to server metadata
+    Reconcile(cmd::reconcile::Options),
+    /// List or restore messages quarantined by `reconcile --quarantine`
+    Quarantine(cmd::quarantine::Options),
+    /// Export the archive as mbox or Maildir
+    Export(cmd::export::Options),
+    /// Apply schema changes to the database
+    Migrate,
+    /// Check that all messages on the server are in the archive
+    Verify(cmd::verify::Options),
+}
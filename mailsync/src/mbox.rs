@@ -0,0 +1,665 @@
+use std::io::{self, Write};
+use std::str::{self, FromStr};
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+
+use crate::Flag;
+
+/// The mbox variants differ in how they delimit and escape message bodies
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `From ` lines in bodies are quoted as `>From `, quoted lines are not
+    /// distinguishable from original ones
+    Mboxo,
+    /// Any `>*From ` line in a body gets an extra `>`, which is reversible
+    Mboxrd,
+    /// Like mboxo, but with a `Content-Length` header delimiting the body
+    Mboxcl,
+    /// Body length given by `Content-Length`, no quoting at all
+    Mboxcl2,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "mboxo" => Format::Mboxo,
+            "mboxrd" => Format::Mboxrd,
+            "mboxcl" => Format::Mboxcl,
+            "mboxcl2" => Format::Mboxcl2,
+            _ => return Err(format!("unknown mbox format {:?}", s)),
+        })
+    }
+}
+
+/// A single message from an mbox file
+pub struct Entry<'a> {
+    /// The `From ` line that started this entry, without line ending
+    pub from_line: &'a [u8],
+    /// The message, with mbox quoting undone
+    pub message: Vec<u8>,
+    /// Headers removed from the message by processing steps, in order
+    pub extracted: Vec<(String, Vec<u8>)>,
+}
+
+impl<'a> Entry<'a> {
+    /// The envelope sender from the `From ` line
+    pub fn sender(&self) -> Option<&str> {
+        let line = str::from_utf8(self.from_line).ok()?;
+        line.get(5..)?.split_whitespace().next()
+    }
+
+    /// The delivery date from the `From ` line
+    pub fn date(&self) -> Option<DateTime<FixedOffset>> {
+        let line = str::from_utf8(self.from_line).ok()?;
+        let rest = line.get(5..)?.trim_start();
+        let date = rest.splitn(2, char::is_whitespace).nth(1)?;
+        from_line_date(&date.split_whitespace().collect::<Vec<_>>().join(" "))
+    }
+
+    /// Remove the named headers, storing them in `extracted`
+    pub fn extract_headers(&mut self, names: &[&str]) {
+        let mut kept = Vec::with_capacity(self.message.len());
+        let mut pos = 0;
+        let mut current: Option<usize> = None;
+        while pos < self.message.len() {
+            let end = match memchr(b'\n', &self.message[pos..]) {
+                Some(i) => pos + i + 1,
+                None => self.message.len(),
+            };
+            let line = &self.message[pos..end];
+            let content = trim_eol(line);
+            if content.is_empty() {
+                // End of the header section; copy the rest unchanged
+                kept.extend_from_slice(&self.message[pos..]);
+                break;
+            }
+
+            let continuation = content[0] == b' ' || content[0] == b'\t';
+            if continuation {
+                if let Some(idx) = current {
+                    let value = &mut self.extracted[idx].1;
+                    value.push(b' ');
+                    value.extend_from_slice(trim(content));
+                } else {
+                    kept.extend_from_slice(line);
+                }
+            } else {
+                current = None;
+                if let Some(colon) = memchr(b':', content) {
+                    let name = String::from_utf8_lossy(&content[..colon]);
+                    if names.iter().any(|n| n.eq_ignore_ascii_case(&name)) {
+                        let value = trim(&content[colon + 1..]).to_vec();
+                        self.extracted.push((name.into_owned(), value));
+                        current = Some(self.extracted.len() - 1);
+                    }
+                }
+                if current.is_none() {
+                    kept.extend_from_slice(line);
+                }
+            }
+            pos = end;
+        }
+        self.message = kept;
+    }
+
+    /// Get the first extracted header with the given name
+    pub fn extracted(&self, name: &str) -> Option<&[u8]> {
+        self.extracted
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+/// A processing step applied to every entry before it is stored
+pub trait Step {
+    fn process(&self, entry: &mut Entry<'_>);
+}
+
+/// Strips the pseudo-headers Gmail's Takeout adds to each message
+pub struct GmailHeaders;
+
+impl GmailHeaders {
+    pub const THREAD_ID: &'static str = "X-GM-THRID";
+    pub const LABELS: &'static str = "X-Gmail-Labels";
+}
+
+impl Step for GmailHeaders {
+    fn process(&self, entry: &mut Entry<'_>) {
+        entry.extract_headers(&[Self::THREAD_ID, Self::LABELS]);
+    }
+}
+
+/// Thread, labels and flags recovered from the Gmail pseudo-headers
+#[derive(Debug, Default)]
+pub struct GmailMeta {
+    /// From the `From ` line, which Takeout fills with `<X-GM-MSGID>@xxx`
+    pub message_id: Option<u64>,
+    pub thread_id: Option<u64>,
+    /// Labels, with system labels named like in IMAP's `X-GM-LABELS` (`\Inbox`)
+    pub labels: Vec<String>,
+    pub flags: Vec<Flag>,
+}
+
+impl GmailMeta {
+    /// Interpret the headers extracted by the `GmailHeaders` step
+    pub fn from_entry(entry: &Entry<'_>) -> Self {
+        let thread_id = entry
+            .extracted(GmailHeaders::THREAD_ID)
+            .and_then(|v| str::from_utf8(v).ok())
+            .and_then(|s| s.trim().parse().ok());
+
+        let message_id = entry
+            .sender()
+            .and_then(|s| s.strip_suffix("@xxx"))
+            .and_then(|id| id.parse().ok());
+
+        let mut meta = GmailMeta {
+            message_id,
+            thread_id,
+            ..Default::default()
+        };
+        let labels = match entry.extracted(GmailHeaders::LABELS) {
+            Some(labels) => parse_labels(&String::from_utf8_lossy(labels)),
+            None => return meta,
+        };
+
+        let mut seen = true;
+        for label in labels {
+            let system = match label.as_str() {
+                "Opened" => continue,
+                "Unread" => {
+                    seen = false;
+                    continue;
+                }
+                // Archived messages are simply those without the Inbox label
+                "Archived" => continue,
+                "Starred" => {
+                    meta.flags.push(Flag::Flagged);
+                    "\\Starred"
+                }
+                "Inbox" => "\\Inbox",
+                "Sent" => "\\Sent",
+                "Important" => "\\Important",
+                "Drafts" | "Draft" => "\\Draft",
+                "Spam" => "\\Spam",
+                "Trash" => "\\Trash",
+                _ => {
+                    meta.labels.push(label);
+                    continue;
+                }
+            };
+            meta.labels.push(system.to_string());
+        }
+
+        if seen {
+            meta.flags.push(Flag::Seen);
+        }
+        meta
+    }
+}
+
+/// Build an `X-Gmail-Labels` value in the form Takeout uses, inverse of `GmailMeta`
+pub fn format_labels(labels: &[String], flags: &[Flag]) -> String {
+    let mut names = labels
+        .iter()
+        .map(|label| match label.as_str() {
+            "\\Inbox" => "Inbox".into(),
+            "\\Sent" => "Sent".into(),
+            "\\Important" => "Important".into(),
+            "\\Starred" => "Starred".into(),
+            "\\Draft" => "Drafts".into(),
+            "\\Spam" => "Spam".into(),
+            "\\Trash" => "Trash".into(),
+            name if name.contains(',') || name.contains('"') => {
+                format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            name => name.to_string(),
+        })
+        .collect::<Vec<String>>();
+
+    if !labels.iter().any(|l| l == "\\Inbox") {
+        names.push("Archived".into());
+    }
+    if flags.contains(&Flag::Seen) {
+        names.push("Opened".into());
+    } else {
+        names.push("Unread".into());
+    }
+    names.join(",")
+}
+
+/// Split an `X-Gmail-Labels` value, which quotes labels containing commas
+pub fn parse_labels(s: &str) -> Vec<String> {
+    let mut labels = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                if let Some(c) = chars.next() {
+                    current.push(c);
+                }
+            }
+            ',' if !quoted => {
+                let label = current.trim();
+                if !label.is_empty() {
+                    labels.push(label.to_string());
+                }
+                current.clear();
+            }
+            c => current.push(c),
+        }
+    }
+
+    let label = current.trim();
+    if !label.is_empty() {
+        labels.push(label.to_string());
+    }
+    labels
+}
+
+/// Iterates over the messages in an mbox file
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: Format,
+    steps: Vec<Box<dyn Step>>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8], format: Format) -> Self {
+        let pos = if data.starts_with(b"From ") {
+            0
+        } else {
+            find_from_line(data, 0).unwrap_or_else(|| data.len())
+        };
+
+        Self {
+            data,
+            pos,
+            format,
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn step(mut self, step: Box<dyn Step>) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Offset into the data where the next message starts
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Find where the message starting at `start` ends
+    fn message_end(&self, start: usize) -> usize {
+        if let Format::Mboxcl | Format::Mboxcl2 = self.format {
+            if let Some(end) = content_length_end(self.data, start) {
+                return end;
+            }
+        }
+        find_from_line(self.data, start).unwrap_or_else(|| self.data.len())
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Entry<'a>> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let line_end = match memchr(b'\n', &self.data[self.pos..]) {
+            Some(i) => self.pos + i + 1,
+            None => self.data.len(),
+        };
+        let from_line = trim_eol(&self.data[self.pos..line_end]);
+        let end = self.message_end(line_end);
+        let raw = strip_separator(&self.data[line_end..end]);
+        self.pos = end;
+
+        let message = match self.format {
+            Format::Mboxo | Format::Mboxcl => unquote(raw, false),
+            Format::Mboxrd => unquote(raw, true),
+            Format::Mboxcl2 => raw.to_vec(),
+        };
+
+        let mut entry = Entry {
+            from_line,
+            message,
+            extracted: Vec::new(),
+        };
+        for step in &self.steps {
+            step.process(&mut entry);
+        }
+        Some(entry)
+    }
+}
+
+/// Writes messages in mboxrd format, which can be read back without loss
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Append a message, prepending the given headers (like `X-Gmail-Labels`)
+    pub fn write(
+        &mut self,
+        sender: &str,
+        dt: &DateTime<FixedOffset>,
+        headers: &[(&str, String)],
+        message: &[u8],
+    ) -> io::Result<()> {
+        // Stick to the line endings used by the message itself
+        let eol: &[u8] = match memchr(b'\n', message) {
+            Some(i) if i > 0 && message[i - 1] == b'\r' => b"\r\n",
+            _ => b"\n",
+        };
+
+        let date = dt.with_timezone(&Utc).format("%a %b %e %T %Y");
+        write!(self.inner, "From {} {}", sender, date)?;
+        self.inner.write_all(eol)?;
+        for (name, value) in headers {
+            write!(self.inner, "{}: {}", name, value)?;
+            self.inner.write_all(eol)?;
+        }
+
+        let mut pos = 0;
+        while pos < message.len() {
+            let end = match memchr(b'\n', &message[pos..]) {
+                Some(i) => pos + i + 1,
+                None => message.len(),
+            };
+
+            let line = &message[pos..end];
+            let quotes = line.iter().take_while(|&&b| b == b'>').count();
+            if line[quotes..].starts_with(b"From ") {
+                self.inner.write_all(b">")?;
+            }
+            self.inner.write_all(line)?;
+            pos = end;
+        }
+
+        if !message.ends_with(b"\n") {
+            self.inner.write_all(eol)?;
+        }
+        self.inner.write_all(eol)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Find the next `From ` line at or after `pos` (which must be at a line start)
+fn find_from_line(data: &[u8], mut pos: usize) -> Option<usize> {
+    while pos < data.len() {
+        if data[pos..].starts_with(b"From ") && (pos == 0 || data[pos - 1] == b'\n') {
+            return Some(pos);
+        }
+        pos += memchr(b'\n', &data[pos..])? + 1;
+    }
+    None
+}
+
+/// Use the `Content-Length` header to find the end of the message at `start`
+///
+/// Returns `None` if the header is missing or the computed end does not line up
+/// with the start of another entry, in which case the caller should fall back to
+/// scanning for `From ` lines.
+fn content_length_end(data: &[u8], start: usize) -> Option<usize> {
+    let mut pos = start;
+    let mut length = None;
+    loop {
+        let end = pos + memchr(b'\n', &data[pos..])? + 1;
+        let line = trim_eol(&data[pos..end]);
+        pos = end;
+        if line.is_empty() {
+            break;
+        }
+
+        let prefix = b"content-length:";
+        if line.len() > prefix.len() && line[..prefix.len()].eq_ignore_ascii_case(prefix) {
+            let value = str::from_utf8(trim(&line[prefix.len()..])).ok()?;
+            length = Some(value.parse::<usize>().ok()?);
+        }
+    }
+
+    // The header may hold any number, so don't trust it to fit
+    let mut end = pos.checked_add(length?)?;
+    if end > data.len() {
+        return None;
+    }
+    while end < data.len() && (data[end] == b'\r' || data[end] == b'\n') {
+        end += 1;
+    }
+    if end == data.len() || data[end..].starts_with(b"From ") {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+/// Undo `>From ` quoting; `all` selects mboxrd semantics (`>>From ` etc.)
+fn unquote(raw: &[u8], all: bool) -> Vec<u8> {
+    let mut message = Vec::with_capacity(raw.len());
+    let mut pos = 0;
+    while pos < raw.len() {
+        let end = match memchr(b'\n', &raw[pos..]) {
+            Some(i) => pos + i + 1,
+            None => raw.len(),
+        };
+
+        let line = &raw[pos..end];
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        let quoted = quotes > 0 && (all || quotes == 1) && line[quotes..].starts_with(b"From ");
+        if quoted {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(line);
+        }
+        pos = end;
+    }
+    message
+}
+
+/// Remove the blank line separating this message from the next `From ` line
+fn strip_separator(raw: &[u8]) -> &[u8] {
+    if raw.ends_with(b"\r\n\r\n") {
+        &raw[..raw.len() - 2]
+    } else if raw.ends_with(b"\n\n") {
+        &raw[..raw.len() - 1]
+    } else {
+        raw
+    }
+}
+
+fn from_line_date(s: &str) -> Option<DateTime<FixedOffset>> {
+    for fmt in &FROM_LINE_ZONED_FORMATS {
+        if let Ok(dt) = DateTime::parse_from_str(s, fmt) {
+            return Some(dt);
+        }
+    }
+
+    // asctime(3) style dates without time zone are in UTC by convention
+    for fmt in &FROM_LINE_NAIVE_FORMATS {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, fmt) {
+            return FixedOffset::east(0).from_local_datetime(&dt).single();
+        }
+    }
+    None
+}
+
+const FROM_LINE_ZONED_FORMATS: [&str; 3] = [
+    "%a %b %e %T %z %Y",
+    "%a %b %e %T %Y %z",
+    "%a, %e %b %Y %T %z",
+];
+
+const FROM_LINE_NAIVE_FORMATS: [&str; 2] = ["%a %b %e %T %Y", "%a %b %e %H:%M %Y"];
+
+fn memchr(needle: u8, haystack: &[u8]) -> Option<usize> {
+    haystack.iter().position(|&b| b == needle)
+}
+
+fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn trim(s: &[u8]) -> &[u8] {
+    let start = s
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(s.len());
+    let end = s
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |i| i + 1);
+    &s[start..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mboxo() {
+        let data =
+            b"From a@b Mon Jan  1 00:00:00 2001\nSubject: one\n\n>From here\n>>From there\n\n\
+                     From c@d Tue Jan  2 00:00:00 2001\nSubject: two\n\nbody\n";
+        let entries = Reader::new(data, Format::Mboxo).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].message,
+            b"Subject: one\n\nFrom here\n>>From there\n".to_vec()
+        );
+        assert_eq!(entries[1].message, b"Subject: two\n\nbody\n".to_vec());
+        assert_eq!(entries[1].sender(), Some("c@d"));
+        assert_eq!(entries[1].date().unwrap().timestamp(), 978_393_600);
+    }
+
+    #[test]
+    fn mboxrd() {
+        let data = b"garbage before the first message\n\
+                     From a@b Mon Jan  1 00:00:00 2001\nSubject: one\n\n>From here\n>>From there\n";
+        let entries = Reader::new(data, Format::Mboxrd).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].message,
+            b"Subject: one\n\nFrom here\n>From there\n".to_vec()
+        );
+        assert_eq!(entries[0].date().unwrap().timestamp(), 978_307_200);
+    }
+
+    #[test]
+    fn mboxcl() {
+        let data = b"From a@b Mon Jan  1 00:00:00 2001\nContent-Length: 11\n\nHi\n>From x\n\n\
+                     From c@d Tue Jan  2 00:00:00 2001\nSubject: two\n\nbody\n";
+        let entries = Reader::new(data, Format::Mboxcl).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].message,
+            b"Content-Length: 11\n\nHi\nFrom x\n".to_vec()
+        );
+        assert_eq!(entries[1].message, b"Subject: two\n\nbody\n".to_vec());
+    }
+
+    #[test]
+    fn mboxcl2() {
+        // The body has an unquoted `From ` line, which only the length tells apart
+        let data = b"From a@b Mon Jan  1 00:00:00 2001\nContent-Length: 23\n\n\
+                     From the start\n>From x\n\n\
+                     From c@d Tue Jan  2 00:00:00 2001\nSubject: two\n\nbody\n";
+        let entries = Reader::new(data, Format::Mboxcl2).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].message,
+            b"Content-Length: 23\n\nFrom the start\n>From x\n".to_vec()
+        );
+        assert_eq!(entries[1].sender(), Some("c@d"));
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        // Lengths that overflow or don't end at another entry fall back to `From ` lines
+        for length in &["18446744073709551615", "3", "1000", "x"] {
+            let data = format!(
+                "From a@b Mon Jan  1 00:00:00 2001\nContent-Length: {}\n\nbody\n\n\
+                 From c@d Tue Jan  2 00:00:00 2001\nSubject: two\n\nbody\n",
+                length
+            );
+            let entries = Reader::new(data.as_bytes(), Format::Mboxcl2).collect::<Vec<_>>();
+            assert_eq!(entries.len(), 2, "Content-Length: {}", length);
+            let expected = format!("Content-Length: {}\n\nbody\n", length);
+            assert_eq!(entries[0].message, expected.into_bytes());
+            assert_eq!(entries[1].message, b"Subject: two\n\nbody\n".to_vec());
+        }
+    }
+
+    #[test]
+    fn crlf() {
+        let data = b"From a@b Mon Jan  1 00:00:00 2001\r\nSubject: one\r\n\r\n>From here\r\n\r\n\
+                     From c@d Tue Jan  2 00:00:00 2001\r\nSubject: two\r\n\r\nbody\r\n";
+        let entries = Reader::new(data, Format::Mboxrd).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].from_line,
+            &b"From a@b Mon Jan  1 00:00:00 2001"[..]
+        );
+        assert_eq!(
+            entries[0].message,
+            b"Subject: one\r\n\r\nFrom here\r\n".to_vec()
+        );
+        assert_eq!(entries[1].message, b"Subject: two\r\n\r\nbody\r\n".to_vec());
+        assert_eq!(entries[0].date().unwrap().timestamp(), 978_307_200);
+    }
+
+    #[test]
+    fn binary() {
+        let data = b"From a@b Mon Jan  1 00:00:00 2001\n\xff\xfe\x00bin\n>From \x80\n";
+        let entries = Reader::new(data, Format::Mboxo).collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].message, b"\xff\xfe\x00bin\nFrom \x80\n".to_vec());
+        assert_eq!(entries[0].sender(), Some("a@b"));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(Reader::new(b"", Format::Mboxrd).next().is_none());
+        assert!(Reader::new(b"no messages\n", Format::Mboxo)
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn write_and_read() {
+        let message = b"Subject: x\n\nFrom me\n>From you\n";
+        let dt = FixedOffset::east(0).ymd(2001, 1, 1).and_hms(0, 0, 0);
+        let mut writer = Writer::new(Vec::new());
+        let headers = [(GmailHeaders::LABELS, "Inbox,Opened".to_string())];
+        writer.write("a@b", &dt, &headers, message).unwrap();
+        let data = writer.into_inner();
+
+        let mut reader = Reader::new(&data, Format::Mboxrd).step(Box::new(GmailHeaders));
+        let entry = reader.next().unwrap();
+        assert!(reader.next().is_none());
+        assert_eq!(entry.message, message.to_vec());
+        assert_eq!(entry.date(), Some(dt));
+
+        let meta = GmailMeta::from_entry(&entry);
+        assert_eq!(meta.labels, vec!["\\Inbox".to_string()]);
+        assert_eq!(meta.flags, vec![Flag::Seen]);
+    }
+}
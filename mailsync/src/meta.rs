@@ -0,0 +1,1068 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, FixedOffset};
+use futures::future::ready;
+use futures::stream::TryStreamExt;
+use serde_derive::{Deserialize, Serialize};
+use tokio_imap::builders::{CommandBuilder, StoreOp};
+use tokio_imap::proto::Command;
+use tokio_imap::types::{Attribute, AttributeValue, MailboxDatum, Response, ResponseCode, Status};
+use tokio_imap::ResponseData;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Row;
+use tracing::{debug, info, instrument, warn};
+
+use crate::accumulate::{AttrSet, Collector, Fetched, FlagUpdate, Leftovers};
+use crate::connection::{Capabilities, Client, Session};
+use crate::{fuzzy_datetime_parser, logging, Flag, SyncError};
+
+/// Columns of the `inbox` table, in the order `MessageMeta::from_row()` expects
+macro_rules! meta_columns {
+    () => {
+        "uid, seq, mod_seq, flags, labels, gm_msgid, gm_thrid, mid, dt, subject, sender"
+    };
+}
+
+/// Envelope-level metadata for a single INBOX message, as kept in the `inbox` table
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageMeta {
+    pub seq: u32,
+    pub uid: u32,
+    pub mod_seq: u64,
+    pub flags: Vec<Flag>,
+    /// Gmail labels, only fetched if the server supports X-GM-EXT-1
+    pub labels: Vec<String>,
+    pub gm_msgid: Option<u64>,
+    pub gm_thrid: Option<u64>,
+    pub mid: Option<String>,
+    pub dt: Option<DateTime<FixedOffset>>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+}
+
+impl MessageMeta {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            uid: row.get::<_, i64>(0) as u32,
+            seq: row.get::<_, i64>(1) as u32,
+            mod_seq: row.get::<_, i64>(2) as u64,
+            flags: row.get(3),
+            labels: row.get(4),
+            gm_msgid: row.get::<_, Option<i64>>(5).map(|id| id as u64),
+            gm_thrid: row.get::<_, Option<i64>>(6).map(|id| id as u64),
+            mid: row.get(7),
+            dt: row.get(8),
+            subject: row.get(9),
+            sender: row.get(10),
+        }
+    }
+
+    pub fn unread(&self) -> bool {
+        !self.flags.contains(&Flag::Seen)
+    }
+
+    pub fn flagged(&self) -> bool {
+        self.flags.contains(&Flag::Flagged)
+    }
+}
+
+/// Conditions on messages for `MetaStore::search()` and `MetaStore::threads()`
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub unread: Option<bool>,
+    pub flagged: Option<bool>,
+    pub label: Option<String>,
+    /// Case-insensitive substring to look for in subject and sender
+    pub text: Option<String>,
+}
+
+/// Summary of a thread, as returned by `MetaStore::threads()`
+#[derive(Debug)]
+pub struct Thread {
+    /// UID of the newest matching message
+    pub uid: u32,
+    /// Subject of the newest matching message
+    pub subject: Option<String>,
+    pub latest: Option<DateTime<FixedOffset>>,
+    pub unread: usize,
+    /// UIDs of the matching messages, newest first
+    pub messages: Vec<u32>,
+}
+
+/// A local change to a message that still has to be pushed to the IMAP server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Action {
+    AddFlag(Flag),
+    RemoveFlag(Flag),
+    /// Move the message out of the INBOX into the given mailbox
+    Move(String),
+    /// Copy the message into the given mailbox (a label, for Gmail)
+    Copy(String),
+}
+
+impl Action {
+    /// Name and argument of the action, as stored in the `inbox_pending` table
+    fn encode(&self) -> (&'static str, &str) {
+        match self {
+            Action::AddFlag(flag) => ("add-flag", flag.as_str()),
+            Action::RemoveFlag(flag) => ("remove-flag", flag.as_str()),
+            Action::Move(mailbox) => ("move", mailbox),
+            Action::Copy(mailbox) => ("copy", mailbox),
+        }
+    }
+
+    /// Statement applying the action to the `inbox` row with UID `$1`, given the encoded
+    /// argument as `$2`
+    ///
+    /// Changes are made in SQL rather than by writing back a modified copy, so that they
+    /// can't overwrite concurrent changes to the same row.
+    fn statement(&self) -> &'static str {
+        match self {
+            Action::AddFlag(_) => {
+                "UPDATE inbox SET flags = CASE WHEN CAST($2::text AS flags) = ANY(flags) \
+                 THEN flags ELSE array_append(flags, CAST($2::text AS flags)) END \
+                 WHERE uid = $1"
+            }
+            Action::RemoveFlag(_) => {
+                "UPDATE inbox SET flags = array_remove(flags, CAST($2::text AS flags)) \
+                 WHERE uid = $1"
+            }
+            Action::Copy(_) => {
+                "UPDATE inbox SET labels = CASE WHEN $2 = ANY(labels) \
+                 THEN labels ELSE array_append(labels, $2) END WHERE uid = $1"
+            }
+            // Moved messages leave the INBOX
+            Action::Move(_) => "DELETE FROM inbox WHERE uid = $1",
+        }
+    }
+
+    fn decode(name: &str, argument: String) -> Option<Action> {
+        Some(match name {
+            "add-flag" => Action::AddFlag(Flag::from_str(&argument)?),
+            "remove-flag" => Action::RemoveFlag(Flag::from_str(&argument)?),
+            "move" => Action::Move(argument),
+            "copy" => Action::Copy(argument),
+            _ => return None,
+        })
+    }
+}
+
+/// INBOX metadata and the local changes that still have to be pushed, kept in Postgres
+/// so that `get-meta` and the web app can use them at the same time
+pub struct MetaStore<'a> {
+    db: &'a tokio_postgres::Client,
+}
+
+impl<'a> MetaStore<'a> {
+    pub fn new(db: &'a tokio_postgres::Client) -> Self {
+        Self { db }
+    }
+
+    pub async fn get(&self, uid: u32) -> Result<Option<MessageMeta>, SyncError> {
+        let query = format!("{} WHERE uid = $1", SELECT_META);
+        let row = self.db.query_opt(query.as_str(), &[&(uid as i64)]).await?;
+        Ok(row.as_ref().map(MessageMeta::from_row))
+    }
+
+    /// All stored metadata, in UID order
+    pub async fn all(&self) -> Result<Vec<MessageMeta>, SyncError> {
+        let query = format!("{} ORDER BY uid", SELECT_META);
+        let rows = self.db.query(query.as_str(), &[]).await?;
+        Ok(rows.iter().map(MessageMeta::from_row).collect())
+    }
+
+    /// Up to `limit` messages matching `filter` with a UID below `before`, newest first
+    pub async fn search(
+        &self,
+        filter: &Filter,
+        before: u32,
+        limit: usize,
+    ) -> Result<Vec<MessageMeta>, SyncError> {
+        let query = format!(
+            "{} WHERE uid < $1 AND {} ORDER BY uid DESC LIMIT $6",
+            SELECT_META, FILTER
+        );
+        let (before, limit) = (before as i64, limit as i64);
+        let text = filter.text.as_ref().map(|t| t.to_lowercase());
+        let params: [&(dyn ToSql + Sync); 6] = [
+            &before,
+            &filter.unread,
+            &filter.flagged,
+            &filter.label,
+            &text,
+            &limit,
+        ];
+        let rows = self.db.query(query.as_str(), &params).await?;
+        Ok(rows.iter().map(MessageMeta::from_row).collect())
+    }
+
+    /// Up to `limit` threads with messages matching `filter`, ordered by their newest
+    /// matching message, starting below the UID `before`
+    ///
+    /// Messages are grouped by Gmail thread ID where known, and otherwise by subject,
+    /// ignoring reply and forward prefixes.
+    pub async fn threads(
+        &self,
+        filter: &Filter,
+        before: u32,
+        limit: usize,
+    ) -> Result<Vec<Thread>, SyncError> {
+        let query = format!(
+            "WITH matching AS (\
+                 SELECT uid, flags, dt, subject, COALESCE(gm_thrid::text, 'subject:' || \
+                     regexp_replace(COALESCE(subject, ''), '^(\\s*(re: |fw: |fwd:))*\\s*', '', 'i')\
+                 ) AS thread FROM inbox WHERE {}\
+             ) SELECT max(uid), (array_agg(subject ORDER BY uid DESC))[1], \
+                 (array_agg(dt ORDER BY uid DESC))[1], \
+                 count(*) FILTER (WHERE NOT '\\Seen'::flags = ANY(flags)), \
+                 array_agg(uid ORDER BY uid DESC) \
+             FROM matching GROUP BY thread HAVING max(uid) < $1 \
+             ORDER BY max(uid) DESC LIMIT $6",
+            FILTER
+        );
+        let (before, limit) = (before as i64, limit as i64);
+        let text = filter.text.as_ref().map(|t| t.to_lowercase());
+        let params: [&(dyn ToSql + Sync); 6] = [
+            &before,
+            &filter.unread,
+            &filter.flagged,
+            &filter.label,
+            &text,
+            &limit,
+        ];
+        let rows = self.db.query(query.as_str(), &params).await?;
+        Ok(rows
+            .iter()
+            .map(|row| Thread {
+                uid: row.get::<_, i64>(0) as u32,
+                subject: row.get(1),
+                latest: row.get(2),
+                unread: row.get::<_, i64>(3) as usize,
+                messages: row
+                    .get::<_, Vec<i64>>(4)
+                    .into_iter()
+                    .map(|uid| uid as u32)
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Labels in use, with the number of messages carrying each, ordered by name
+    pub async fn labels(&self) -> Result<Vec<(String, usize)>, SyncError> {
+        let rows = self
+            .db
+            .query(
+                "SELECT unnest(labels), count(*) FROM inbox GROUP BY 1 ORDER BY 1 COLLATE \"C\"",
+                &[],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), row.get::<_, i64>(1) as usize))
+            .collect())
+    }
+
+    /// The `limit` most recent messages, newest first
+    pub async fn latest(&self, limit: usize) -> Result<Vec<MessageMeta>, SyncError> {
+        let query = format!("{} ORDER BY uid DESC LIMIT $1", SELECT_META);
+        let rows = self.db.query(query.as_str(), &[&(limit as i64)]).await?;
+        Ok(rows.iter().map(MessageMeta::from_row).collect())
+    }
+
+    /// Bring the stored metadata up to date with the INBOX on the server
+    ///
+    /// Only fetches messages changed since the last update, unless the server doesn't
+    /// support CONDSTORE or UIDVALIDITY has changed. Entries for messages that are no
+    /// longer in the INBOX are removed; without QRESYNC (see `enable_qresync()`), finding
+    /// these may take fetching all UIDs. Local labels and unpushed changes are kept.
+    #[instrument(skip(self, session), fields(mailbox = "INBOX"))]
+    pub async fn update(&self, session: &mut Session) -> Result<Update, SyncError> {
+        let mailbox = examine(session).await?;
+        debug!(?mailbox, "examined");
+        let (valid, changed_since) = resume_point(self.state().await?, &mailbox);
+        let mut update = Update::default();
+        if !valid {
+            info!("no valid state for the mailbox, fetching all metadata");
+            update.full = true;
+        } else if changed_since.is_none() {
+            // Existing entries are updated in place, keeping their local labels
+            info!("server does not support CONDSTORE, fetching all metadata");
+            update.full = true;
+        }
+
+        let mut fetched = Vec::new();
+        let mut vanished = Ok(Vec::new());
+        if mailbox.exists > 0 {
+            let total = match changed_since {
+                Some(_) => None,
+                None => Some(mailbox.exists as u64),
+            };
+            let bar = logging::progress(total, "messages");
+            vanished = fetch_envelopes(session, changed_since, |item| {
+                if let Fetched::Message(_) = item {
+                    bar.inc(1);
+                }
+                fetched.push(item);
+                Ok(())
+            })
+            .await;
+            bar.finish_and_clear();
+        }
+
+        // Entries for UIDs from another validity period could linger, so start from scratch,
+        // but only once all metadata came in. Labels only exist locally without Gmail's
+        // extensions, so they are carried over to the new UIDs by Message-ID.
+        let vanished = match vanished {
+            Err(e) if !valid => return Err(e),
+            vanished => vanished,
+        };
+        let mut local_labels = HashMap::new();
+        if !valid {
+            let rows = self
+                .db
+                .query(
+                    "DELETE FROM inbox WHERE gm_msgid IS NULL AND mid IS NOT NULL \
+                     AND cardinality(labels) > 0 RETURNING mid, labels",
+                    &[],
+                )
+                .await?;
+            for row in rows {
+                local_labels.insert(row.get::<_, String>(0), row.get::<_, Vec<String>>(1));
+            }
+            self.db.execute("DELETE FROM inbox", &[]).await?;
+        }
+
+        // Keep what did come in if the fetch was incomplete
+        for item in fetched {
+            match item {
+                Fetched::Message(mut meta) => {
+                    if let Some(labels) = meta.mid.as_ref().and_then(|mid| local_labels.get(mid)) {
+                        meta.labels = labels.clone();
+                    }
+                    self.insert(&meta).await?;
+                    update.fetched += 1;
+                }
+                Fetched::Flags(flags) => self.update_flags(&flags).await?,
+            }
+        }
+        // Queued UIDs are meaningless after UIDVALIDITY changed
+        if valid {
+            self.reapply_pending().await?;
+        }
+        update.removed = self.remove_vanished(&vanished?).await?;
+
+        // New messages are always fetched, so if the number of stored messages matches,
+        // none of them were expunged. Otherwise, and unless the server reported them
+        // through QRESYNC, they have to be found by fetching all UIDs.
+        if !self.renumbered(mailbox.exists).await? {
+            update.removed += self.remove_expunged(session, mailbox.exists).await?;
+        }
+
+        match mailbox.uid_validity {
+            Some(uid_validity) => {
+                let highest_mod_seq = mailbox.highest_mod_seq.map(|ms| ms as i64);
+                self.db
+                    .execute(
+                        "INSERT INTO inbox_state (mailbox, uid_validity, highest_mod_seq) \
+                         VALUES ($1, $2, $3) \
+                         ON CONFLICT (mailbox) DO UPDATE SET \
+                         uid_validity = EXCLUDED.uid_validity, \
+                         highest_mod_seq = EXCLUDED.highest_mod_seq",
+                        &[&MAILBOX, &(uid_validity as i64), &highest_mod_seq],
+                    )
+                    .await?;
+            }
+            None => {
+                self.db
+                    .execute("DELETE FROM inbox_state WHERE mailbox = $1", &[&MAILBOX])
+                    .await?;
+            }
+        }
+
+        Ok(update)
+    }
+
+    /// Like `update()`, but only reports what would change, without storing anything
+    #[instrument(skip(self, session), fields(mailbox = "INBOX"))]
+    pub async fn check(&self, session: &mut Session) -> Result<Update, SyncError> {
+        let mailbox = examine(session).await?;
+        debug!(?mailbox, "examined");
+        let (valid, changed_since) = resume_point(self.state().await?, &mailbox);
+        let mut update = Update {
+            full: changed_since.is_none(),
+            ..Update::default()
+        };
+
+        let mut vanished = Vec::new();
+        if mailbox.exists > 0 {
+            let fetched = &mut update.fetched;
+            vanished = fetch_envelopes(session, changed_since, |item| {
+                if let Fetched::Message(_) = item {
+                    *fetched += 1;
+                }
+                Ok(())
+            })
+            .await?;
+        }
+
+        let stored = self.db.query("SELECT uid FROM inbox", &[]).await?;
+        let stored = stored.iter().map(|row| row.get::<_, i64>(0) as u32);
+        update.removed = match valid {
+            false => stored.count(),
+            true if session.capabilities.qresync_enabled && changed_since.is_some() => {
+                let vanished = vanished.into_iter().collect::<HashSet<_>>();
+                stored.filter(|uid| vanished.contains(uid)).count()
+            }
+            true => {
+                let uids = match mailbox.exists {
+                    0 => HashMap::new(),
+                    _ => fetch_uids(&mut session.client).await?,
+                };
+                stored.filter(|uid| !uids.contains_key(uid)).count()
+            }
+        };
+        Ok(update)
+    }
+
+    async fn state(&self) -> Result<Option<MailboxState>, SyncError> {
+        let row = self
+            .db
+            .query_opt(
+                "SELECT uid_validity, highest_mod_seq FROM inbox_state WHERE mailbox = $1",
+                &[&MAILBOX],
+            )
+            .await?;
+        Ok(row.map(|row| MailboxState {
+            uid_validity: row.get::<_, i64>(0) as u32,
+            highest_mod_seq: row.get::<_, Option<i64>>(1).map(|ms| ms as u64),
+        }))
+    }
+
+    /// Remove messages the server reported as expunged, returning how many were stored
+    async fn remove_vanished(&self, uids: &[u32]) -> Result<usize, SyncError> {
+        let uids = uids.iter().map(|&uid| uid as i64).collect::<Vec<_>>();
+        let removed = self
+            .db
+            .query(
+                "DELETE FROM inbox WHERE uid = ANY($1) RETURNING uid",
+                &[&uids],
+            )
+            .await?;
+        for row in &removed {
+            debug!(uid = row.get::<_, i64>(0), "removed vanished message");
+        }
+        Ok(removed.len())
+    }
+
+    /// Derive sequence numbers from the stored UIDs, if all messages are stored
+    ///
+    /// Returns `false` if the number of stored messages doesn't match `exists`.
+    async fn renumbered(&self, exists: u32) -> Result<bool, SyncError> {
+        let count = self
+            .db
+            .query_one("SELECT count(*) FROM inbox", &[])
+            .await?
+            .get::<_, i64>(0);
+        if count != exists as i64 {
+            return Ok(false);
+        }
+
+        self.db
+            .execute(
+                "UPDATE inbox SET seq = numbered.seq \
+                 FROM (SELECT uid, row_number() OVER (ORDER BY uid) AS seq FROM inbox) numbered \
+                 WHERE inbox.uid = numbered.uid AND inbox.seq <> numbered.seq",
+                &[],
+            )
+            .await?;
+        Ok(true)
+    }
+
+    /// Remove messages that are no longer in the mailbox and fix up sequence numbers,
+    /// by fetching the current UID for every message
+    async fn remove_expunged(
+        &self,
+        session: &mut Session,
+        exists: u32,
+    ) -> Result<usize, SyncError> {
+        let uids = match exists {
+            0 => HashMap::new(),
+            _ => fetch_uids(&mut session.client).await?,
+        };
+        let (uids, seqs): (Vec<i64>, Vec<i64>) = uids
+            .into_iter()
+            .map(|(uid, seq)| (uid as i64, seq as i64))
+            .unzip();
+        let removed = self
+            .db
+            .query(
+                "DELETE FROM inbox WHERE uid <> ALL($1) RETURNING uid",
+                &[&uids],
+            )
+            .await?;
+        for row in &removed {
+            debug!(uid = row.get::<_, i64>(0), "removed expunged message");
+        }
+
+        self.db
+            .execute(
+                "UPDATE inbox SET seq = current.seq \
+                 FROM unnest($1::BIGINT[], $2::BIGINT[]) AS current (uid, seq) \
+                 WHERE inbox.uid = current.uid AND inbox.seq <> current.seq",
+                &[&uids, &seqs],
+            )
+            .await?;
+        Ok(removed.len())
+    }
+
+    /// Store the given metadata, replacing what was stored for its UID
+    ///
+    /// Labels are only replaced if they came from the server, which is the case if it
+    /// supports Gmail's extensions; otherwise labels only exist locally and are kept.
+    pub async fn insert(&self, meta: &MessageMeta) -> Result<(), SyncError> {
+        self.db
+            .execute(
+                "INSERT INTO inbox (uid, seq, mod_seq, flags, labels, gm_msgid, gm_thrid, mid, \
+                                    dt, subject, sender) \
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+                 ON CONFLICT (uid) DO UPDATE SET seq = EXCLUDED.seq, \
+                 mod_seq = EXCLUDED.mod_seq, flags = EXCLUDED.flags, \
+                 labels = CASE WHEN EXCLUDED.gm_msgid IS NULL THEN inbox.labels \
+                          ELSE EXCLUDED.labels END, \
+                 gm_msgid = EXCLUDED.gm_msgid, gm_thrid = EXCLUDED.gm_thrid, mid = EXCLUDED.mid, \
+                 dt = EXCLUDED.dt, subject = EXCLUDED.subject, sender = EXCLUDED.sender",
+                &[
+                    &(meta.uid as i64),
+                    &(meta.seq as i64),
+                    &(meta.mod_seq as i64),
+                    &meta.flags,
+                    &meta.labels,
+                    &meta.gm_msgid.map(|id| id as i64),
+                    &meta.gm_thrid.map(|id| id as i64),
+                    &meta.mid,
+                    &meta.dt,
+                    &meta.subject,
+                    &meta.sender,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Apply flags the server reported for a message during a fetch
+    pub async fn update_flags(&self, update: &FlagUpdate) -> Result<(), SyncError> {
+        let uid = match update.uid {
+            Some(uid) => uid,
+            None => {
+                debug!(seq = update.seq, "ignoring flag update without UID");
+                return Ok(());
+            }
+        };
+
+        let mod_seq = update.mod_seq.unwrap_or(0) as i64;
+        self.db
+            .execute(
+                "UPDATE inbox SET flags = $1, mod_seq = GREATEST(mod_seq, $2) WHERE uid = $3",
+                &[&update.flags, &mod_seq, &(uid as i64)],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Apply the action to the local copy and queue it for pushing to the server
+    ///
+    /// Returns the updated metadata, or `None` if the message is no longer in the INBOX.
+    /// Queued changes are pushed by `mailsync get-meta`, which can run alongside the web
+    /// app (from a timer, for example).
+    pub async fn apply(&self, uid: u32, action: Action) -> Result<Option<MessageMeta>, SyncError> {
+        let (name, argument) = action.encode();
+        // Queue the change in the same statement, so it can't get lost, and only if the
+        // message was still there to change
+        let query = format!(
+            "WITH changed AS ({} RETURNING {}), \
+             queued AS (INSERT INTO inbox_pending (uid, action, argument) \
+                        SELECT uid, $3, $2 FROM changed) \
+             SELECT * FROM changed",
+            action.statement(),
+            meta_columns!()
+        );
+        let row = self
+            .db
+            .query_opt(query.as_str(), &[&(uid as i64), &argument, &name])
+            .await?;
+        Ok(match action {
+            Action::Move(_) => None,
+            _ => row.as_ref().map(MessageMeta::from_row),
+        })
+    }
+
+    /// Apply changes that haven't been pushed yet to freshly fetched metadata
+    async fn reapply_pending(&self) -> Result<(), SyncError> {
+        let rows = self
+            .db
+            .query(
+                "SELECT uid, action, argument FROM inbox_pending ORDER BY id",
+                &[],
+            )
+            .await?;
+        for row in rows {
+            let uid: i64 = row.get(0);
+            let action = match Action::decode(row.get(1), row.get(2)) {
+                Some(action) => action,
+                None => continue,
+            };
+            let (_, argument) = action.encode();
+            self.db
+                .execute(action.statement(), &[&uid, &argument])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Number of changes waiting to be pushed to the server
+    pub async fn pending_changes(&self) -> Result<usize, SyncError> {
+        let row = self
+            .db
+            .query_one("SELECT count(*) FROM inbox_pending", &[])
+            .await?;
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+
+    /// Push queued changes to the server, in the order they were made
+    ///
+    /// Selects INBOX read-write. Changes are removed from the queue
+    /// only after the server has accepted them; rejected changes stay queued, along with
+    /// any later changes to the same message. Without MOVE support, messages are
+    /// copied and marked deleted instead, and expunged if the server supports UIDPLUS.
+    pub async fn push_changes(&self, session: &mut Session) -> Result<Pushed, SyncError> {
+        let cmd = CommandBuilder::select("INBOX");
+        let msgs = session.client.call(cmd).try_collect::<Vec<_>>().await?;
+        let mailbox = Mailbox::from_responses(&msgs);
+        if let (Some(state), Some(validity)) = (self.state().await?, mailbox.uid_validity) {
+            if state.uid_validity != validity {
+                // The UIDs in the queue may refer to other messages now
+                let dropped = self.db.execute("DELETE FROM inbox_pending", &[]).await?;
+                warn!(
+                    changes = dropped,
+                    "UIDVALIDITY changed, dropping queued changes"
+                );
+                return Ok(Pushed::default());
+            }
+        }
+
+        let rows = self
+            .db
+            .query(
+                "SELECT id, uid, action, argument FROM inbox_pending ORDER BY id",
+                &[],
+            )
+            .await?;
+
+        let caps = &session.capabilities;
+        let mut pushed = Pushed::default();
+        let mut failed = HashSet::new();
+        for row in rows {
+            let id: i64 = row.get(0);
+            let uid = row.get::<_, i64>(1) as u32;
+            let action = match Action::decode(row.get(2), row.get(3)) {
+                Some(action) => action,
+                None => {
+                    warn!(id, uid, "dropping unknown change");
+                    self.db
+                        .execute("DELETE FROM inbox_pending WHERE id = $1", &[&id])
+                        .await?;
+                    continue;
+                }
+            };
+
+            if failed.contains(&uid) {
+                debug!(
+                    uid,
+                    ?action,
+                    "holding back change after an earlier one failed"
+                );
+                pushed.held += 1;
+                continue;
+            }
+
+            debug!(uid, ?action, "pushing change");
+            let cmds = match &action {
+                Action::AddFlag(flag) => vec![CommandBuilder::uid_store(
+                    uid,
+                    StoreOp::AddFlags,
+                    &[flag.as_str()],
+                )],
+                Action::RemoveFlag(flag) => vec![CommandBuilder::uid_store(
+                    uid,
+                    StoreOp::RemoveFlags,
+                    &[flag.as_str()],
+                )],
+                Action::Move(mailbox) if caps.moves => vec![CommandBuilder::uid_move(uid, mailbox)],
+                // The commands run in order and stop at the first one that fails, so the
+                // message is only marked deleted once the copy is known to exist
+                Action::Move(mailbox) => {
+                    let mut cmds = vec![
+                        CommandBuilder::uid_copy(uid, mailbox),
+                        CommandBuilder::uid_store(uid, StoreOp::AddFlags, &["\\Deleted"]),
+                    ];
+                    match caps.uidplus {
+                        true => cmds.push(CommandBuilder::uid_expunge(uid)),
+                        false => warn!(uid, "cannot expunge moved message"),
+                    }
+                    cmds
+                }
+                Action::Copy(mailbox) => vec![CommandBuilder::uid_copy(uid, mailbox)],
+            };
+
+            let mut accepted = true;
+            for cmd in cmds {
+                if !accepted_by_server(&mut session.client, cmd).await? {
+                    accepted = false;
+                    break;
+                }
+            }
+
+            match accepted {
+                true => {
+                    self.db
+                        .execute("DELETE FROM inbox_pending WHERE id = $1", &[&id])
+                        .await?;
+                    pushed.pushed += 1;
+                }
+                false => {
+                    warn!(uid, ?action, "server rejected change, keeping it queued");
+                    failed.insert(uid);
+                    pushed.held += 1;
+                }
+            }
+        }
+        Ok(pushed)
+    }
+}
+
+/// What happened to the queued changes during `MetaStore::push_changes()`
+#[derive(Debug, Default)]
+pub struct Pushed {
+    pub pushed: usize,
+    /// Changes that were rejected or held back, and remain queued
+    pub held: usize,
+}
+
+/// Run a command, returning whether the server completed it with OK
+async fn accepted_by_server(client: &mut Client, cmd: Command) -> Result<bool, SyncError> {
+    let responses = client.call(cmd).try_collect::<Vec<_>>().await?;
+    for rd in &responses {
+        if let Response::Done {
+            status,
+            information,
+            ..
+        } = rd.parsed()
+        {
+            if *status != Status::Ok {
+                let reason = information.unwrap_or("no reason given");
+                warn!(?status, reason, "command failed");
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// State of the INBOX as reported by EXAMINE
+#[derive(Debug)]
+pub struct Mailbox {
+    pub exists: u32,
+    pub uid_validity: Option<u32>,
+    /// Only available if the server supports CONDSTORE
+    pub highest_mod_seq: Option<u64>,
+}
+
+impl Mailbox {
+    /// Collect the state from the responses to SELECT or EXAMINE
+    fn from_responses(msgs: &[ResponseData]) -> Self {
+        let mut mailbox = Mailbox {
+            exists: 0,
+            uid_validity: None,
+            highest_mod_seq: None,
+        };
+        for rd in msgs {
+            match rd.parsed() {
+                Response::MailboxData(MailboxDatum::Exists(num)) => mailbox.exists = *num,
+                Response::Data {
+                    code: Some(ResponseCode::UidValidity(val)),
+                    ..
+                } => mailbox.uid_validity = Some(*val),
+                Response::Data {
+                    code: Some(ResponseCode::HighestModSeq(val)),
+                    ..
+                } => mailbox.highest_mod_seq = Some(*val),
+                _ => {}
+            }
+        }
+        mailbox
+    }
+}
+
+/// Examine INBOX (enabling CONDSTORE if available), so that it can be fetched from
+pub async fn examine(session: &mut Session) -> Result<Mailbox, SyncError> {
+    let cmd = CommandBuilder::examine("INBOX");
+    let msgs = match session.capabilities.condstore {
+        true => session.client.call(cmd.cond_store()),
+        false => session.client.call(cmd),
+    }
+    .try_collect::<Vec<_>>()
+    .await?;
+    Ok(Mailbox::from_responses(&msgs))
+}
+
+/// Fetch envelope metadata for the messages in the examined mailbox
+///
+/// With `changed_since`, only messages with a higher mod-seq are fetched; this requires
+/// CONDSTORE, without which mod-seqs are left at zero. Calls `f` for each message as its
+/// metadata comes in, and for flag updates the server sends along the way.
+///
+/// If QRESYNC is enabled, returns the UIDs of messages expunged since `changed_since`.
+pub async fn fetch_envelopes<F>(
+    session: &mut Session,
+    changed_since: Option<u64>,
+    mut f: F,
+) -> Result<Vec<u32>, SyncError>
+where
+    F: FnMut(Fetched<MessageMeta>) -> Result<(), SyncError>,
+{
+    let Capabilities {
+        condstore,
+        qresync_enabled,
+        gmail,
+        ..
+    } = session.capabilities;
+    // UID FETCH, so that unsolicited responses can be told apart by their lack of a UID
+    let mut requested = AttrSet::UID | AttrSet::FLAGS | AttrSet::ENVELOPE;
+    let mut cmd = CommandBuilder::uid_fetch()
+        .range_from(1..)
+        .attr(Attribute::Uid)
+        .attr(Attribute::Flags)
+        .attr(Attribute::Envelope);
+    if gmail {
+        requested = requested | AttrSet::GMAIL;
+        cmd = cmd
+            .attr(Attribute::GmailMsgId)
+            .attr(Attribute::GmailThrId)
+            .attr(Attribute::GmailLabels);
+    }
+    if condstore {
+        requested = requested | AttrSet::MOD_SEQ;
+        cmd = cmd.attr(Attribute::ModSeq);
+        if let Some(mod_seq) = changed_since {
+            cmd = cmd.changed_since(mod_seq);
+            if qresync_enabled {
+                cmd = cmd.vanished();
+            }
+        }
+    }
+
+    let mut result = Ok(());
+    let mut vanished = Vec::new();
+    let acc = session
+        .client
+        .call(cmd)
+        .try_fold(EnvelopeAccumulator::new(requested), |acc, rd| {
+            if let Response::Vanished { uids, .. } = rd.parsed() {
+                vanished.extend(uids.iter().flat_map(|range| range.clone()));
+            }
+            let (new, fetched) = acc.push(rd);
+            if let Some(fetched) = fetched {
+                if result.is_ok() {
+                    result = f(fetched);
+                }
+            }
+            ready(Ok(new))
+        })
+        .await?;
+
+    // Incomplete messages are fetched again on the next update, as the stored mod-seq
+    // only advances when all of them came in
+    let leftovers = acc.finish();
+    let incomplete = leftovers.error();
+    for update in leftovers.updates {
+        result = result.and_then(|_| f(Fetched::Flags(update)));
+    }
+    match incomplete {
+        Some(e) => Err(e),
+        None => result.map(|_| vanished),
+    }
+}
+
+/// Enable QRESYNC if the server supports it, so that `MetaStore::update()` learns about
+/// expunged messages without fetching all UIDs
+///
+/// Has to be called before a mailbox is selected.
+pub async fn enable_qresync(session: &mut Session) -> Result<(), SyncError> {
+    if session.capabilities.qresync {
+        let cmd = CommandBuilder::enable(&["QRESYNC"]);
+        session.capabilities.qresync_enabled = accepted_by_server(&mut session.client, cmd).await?;
+    }
+    Ok(())
+}
+
+/// Fetch the current sequence number for each UID in the examined mailbox
+async fn fetch_uids(client: &mut Client) -> Result<HashMap<u32, u32>, SyncError> {
+    let cmd = CommandBuilder::fetch().range_from(1..).attr(Attribute::Uid);
+    Ok(client
+        .call(cmd)
+        .try_fold(HashMap::new(), |mut uids, rd| {
+            if let Response::Fetch(seq, attr_vals) = rd.parsed() {
+                for val in attr_vals.iter() {
+                    if let AttributeValue::Uid(uid) = val {
+                        uids.insert(*uid, *seq);
+                    }
+                }
+            }
+            ready(Ok(uids))
+        })
+        .await?)
+}
+
+/// What changed in the store during `MetaStore::update()`
+#[derive(Debug, Default)]
+pub struct Update {
+    /// Whether all metadata was fetched (first run, or UIDVALIDITY changed)
+    pub full: bool,
+    pub fetched: usize,
+    pub removed: usize,
+}
+
+/// Whether the stored state is still valid for the mailbox, and if so, the mod-seq to
+/// fetch changes since (if the server supports CONDSTORE)
+fn resume_point(state: Option<MailboxState>, mailbox: &Mailbox) -> (bool, Option<u64>) {
+    match (state, mailbox.uid_validity) {
+        (Some(state), Some(validity)) if state.uid_validity == validity => {
+            (true, state.highest_mod_seq)
+        }
+        _ => (false, None),
+    }
+}
+
+/// Mailbox state as of the last update, stored in the `inbox_state` table
+#[derive(Debug)]
+struct MailboxState {
+    uid_validity: u32,
+    /// Only available if the server supports CONDSTORE
+    highest_mod_seq: Option<u64>,
+}
+
+/// Collects the parts of FETCH responses for UID, MODSEQ, FLAGS, ENVELOPE and the
+/// Gmail attributes
+struct EnvelopeAccumulator {
+    collector: Collector,
+}
+
+impl EnvelopeAccumulator {
+    fn new(requested: AttrSet) -> EnvelopeAccumulator {
+        EnvelopeAccumulator {
+            collector: Collector::new(requested),
+        }
+    }
+
+    fn push(mut self, rd: ResponseData) -> (Self, Option<Fetched<MessageMeta>>) {
+        use AttributeValue::*;
+        let rds = match self.collector.push(rd) {
+            Some(Fetched::Message(rds)) => rds,
+            Some(Fetched::Flags(update)) => return (self, Some(Fetched::Flags(update))),
+            None => return (self, None),
+        };
+
+        let mut seq = None;
+        let mut mod_seq = None;
+        let mut uid = None;
+        let mut mid = None;
+        let mut dt = None;
+        let mut subject = None;
+        let mut sender = None;
+        let mut flags = Vec::new();
+        let mut labels = Vec::new();
+        let mut gm_msgid = None;
+        let mut gm_thrid = None;
+        for rd in rds {
+            if let Response::Fetch(rsp_seq, attr_vals) = rd.parsed() {
+                seq = Some(*rsp_seq);
+                for val in attr_vals.iter() {
+                    match *val {
+                        Uid(u) => {
+                            uid = Some(u);
+                        }
+                        ModSeq(ms) => {
+                            mod_seq = Some(ms);
+                        }
+                        Flags(ref fs) => {
+                            // Later responses may carry updated flags
+                            flags.clear();
+                            flags.extend(fs.iter().filter_map(|f| Flag::from_str(f)));
+                        }
+                        GmailMsgId(id) => {
+                            gm_msgid = Some(id);
+                        }
+                        GmailThrId(id) => {
+                            gm_thrid = Some(id);
+                        }
+                        GmailLabels(ref ls) => {
+                            labels.extend(ls.iter().map(|l| l.to_string()));
+                        }
+                        Envelope(ref env) => {
+                            mid = env.message_id.map(|r| String::from_utf8_lossy(r).into());
+                            let date = env.date.map(String::from_utf8_lossy);
+                            dt = date.as_deref().and_then(fuzzy_datetime_parser);
+                            if let (None, Some(date)) = (dt, &date) {
+                                warn!(uid = ?uid, date = %date, "failed to parse date");
+                            }
+
+                            subject = env.subject.map(|r| String::from_utf8_lossy(r).into());
+                            if let Some(ref senders) = env.sender {
+                                sender = Some(format!(
+                                    "{} <{}@{}>",
+                                    String::from_utf8_lossy(senders[0].name.unwrap_or(b"")),
+                                    String::from_utf8_lossy(senders[0].mailbox.unwrap_or(b"")),
+                                    String::from_utf8_lossy(senders[0].host.unwrap_or(b"")),
+                                ));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            };
+        }
+
+        (
+            self,
+            Some(Fetched::Message(MessageMeta {
+                seq: seq.unwrap(),
+                uid: uid.unwrap(),
+                mod_seq: mod_seq.unwrap_or(0),
+                flags,
+                labels,
+                gm_msgid,
+                gm_thrid,
+                mid,
+                dt,
+                subject,
+                sender,
+            })),
+        )
+    }
+
+    fn finish(self) -> Leftovers {
+        self.collector.finish()
+    }
+}
+
+const SELECT_META: &str = concat!("SELECT ", meta_columns!(), " FROM inbox");
+
+/// Conditions for a `Filter`, taking its fields as `$2` to `$5` (with the text lowercased)
+const FILTER: &str = "($2::bool IS NULL OR (NOT '\\Seen'::flags = ANY(flags)) = $2) \
+                      AND ($3::bool IS NULL OR ('\\Flagged'::flags = ANY(flags)) = $3) \
+                      AND ($4::text IS NULL OR $4 = ANY(labels)) \
+                      AND ($5::text IS NULL OR strpos(lower(subject), $5) > 0 \
+                           OR strpos(lower(sender), $5) > 0)";
+
+/// Key for the metadata's row in the `inbox_state` table
+const MAILBOX: &str = "INBOX";
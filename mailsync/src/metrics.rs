@@ -0,0 +1,92 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+
+pub use prometheus::TEXT_FORMAT;
+
+lazy_static! {
+    pub static ref MESSAGES_FETCHED: IntCounterVec = register_int_counter_vec!(
+        "mailsync_messages_fetched_total",
+        "Messages fetched from the IMAP server",
+        &["folder"]
+    )
+    .unwrap();
+    pub static ref BYTES_FETCHED: IntCounterVec = register_int_counter_vec!(
+        "mailsync_fetched_bytes_total",
+        "Size of the messages fetched from the IMAP server",
+        &["folder"]
+    )
+    .unwrap();
+    pub static ref ERRORS: IntCounterVec =
+        register_int_counter_vec!("mailsync_errors_total", "Errors by kind", &["kind"]).unwrap();
+    pub static ref LAST_SUCCESS: IntGaugeVec = register_int_gauge_vec!(
+        "mailsync_last_success_timestamp_seconds",
+        "Time of the last successful sync",
+        &["folder"]
+    )
+    .unwrap();
+    pub static ref UID_LAG: IntGaugeVec = register_int_gauge_vec!(
+        "mailsync_uid_lag",
+        "Difference between the last UID on the server (UIDNEXT - 1) and the highest local UID",
+        &["folder"]
+    )
+    .unwrap();
+}
+
+/// Set the sync health gauges from the `sync_status` table
+///
+/// Used by processes that don't sync themselves, like the web app.
+pub async fn load_status(db: &tokio_postgres::Client) -> Result<(), tokio_postgres::Error> {
+    let rows = db
+        .query(
+            "SELECT folder, EXTRACT(EPOCH FROM last_success)::BIGINT, uid_next, max_uid \
+             FROM sync_status",
+            &[],
+        )
+        .await?;
+    for row in rows {
+        let folder: &str = row.get(0);
+        // Not set until the first sync of the folder has completed
+        if let Some(last_success) = row.get(1) {
+            LAST_SUCCESS.with_label_values(&[folder]).set(last_success);
+        }
+        let (uid_next, max_uid): (Option<i64>, Option<i64>) = (row.get(2), row.get(3));
+        if let Some(uid_next) = uid_next {
+            UID_LAG
+                .with_label_values(&[folder])
+                .set(uid_next - 1 - max_uid.unwrap_or(0));
+        }
+    }
+    Ok(())
+}
+
+/// Render all registered metrics in the Prometheus text format
+pub fn encode() -> Vec<u8> {
+    let mut buf = Vec::new();
+    let encoder = TextEncoder::new();
+    encoder.encode(&prometheus::gather(), &mut buf).unwrap();
+    buf
+}
+
+/// Serve metrics over HTTP on the given address, for any path
+pub async fn serve(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|_| async {
+            Ok::<_, Infallible>(
+                Response::builder()
+                    .header(CONTENT_TYPE, TEXT_FORMAT)
+                    .body(Body::from(encode()))
+                    .unwrap(),
+            )
+        }))
+    });
+    Server::bind(&addr).serve(make_svc).await
+}
@@ -0,0 +1,221 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request};
+use hyper_rustls::HttpsConnector;
+use serde_derive::Deserialize;
+use tracing::{debug, warn};
+
+use crate::credentials::{CredentialError, Password};
+
+/// OAuth2 settings for an account; the refresh token comes from the password source
+#[derive(Clone, Deserialize)]
+pub struct OAuth2Config {
+    #[serde(default)]
+    pub mechanism: Mechanism,
+    #[serde(default = "OAuth2Config::default_token_url")]
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Option<Password>,
+    /// File to cache access tokens in between runs
+    pub token_cache: Option<PathBuf>,
+}
+
+impl OAuth2Config {
+    fn default_token_url() -> String {
+        "https://oauth2.googleapis.com/token".into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mechanism {
+    XOAuth2,
+    OAuthBearer,
+}
+
+impl Mechanism {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mechanism::XOAuth2 => "XOAUTH2",
+            Mechanism::OAuthBearer => "OAUTHBEARER",
+        }
+    }
+}
+
+impl Default for Mechanism {
+    fn default() -> Self {
+        Mechanism::XOAuth2
+    }
+}
+
+/// Hands out access tokens, refreshing them when they have (nearly) expired
+pub struct Session {
+    config: OAuth2Config,
+    refresh_token: Password,
+    token: Mutex<Option<Token>>,
+}
+
+impl Session {
+    pub fn new(config: OAuth2Config, refresh_token: Password) -> Self {
+        Self {
+            config,
+            refresh_token,
+            token: Mutex::new(None),
+        }
+    }
+
+    pub fn mechanism(&self) -> Mechanism {
+        self.config.mechanism
+    }
+
+    /// Get a valid access token, from memory, the cache file or the token endpoint
+    pub async fn access_token(&self) -> Result<Password, CredentialError> {
+        let now = Utc::now().timestamp();
+        {
+            let mut cached = self.token.lock().unwrap();
+            if cached.is_none() {
+                *cached = self.read_cache();
+            }
+            if let Some(token) = &*cached {
+                if token.expires_at > now + EXPIRY_MARGIN {
+                    return Ok(token.access_token.clone());
+                }
+            }
+        }
+
+        let token = self.refresh().await?;
+        let access_token = token.access_token.clone();
+        self.write_cache(&token);
+        *self.token.lock().unwrap() = Some(token);
+        Ok(access_token)
+    }
+
+    /// Forget the current access token, after the server rejected it
+    pub fn invalidate(&self) {
+        *self.token.lock().unwrap() = None;
+        if let Some(path) = &self.config.token_cache {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// The SASL initial client response for the configured mechanism
+    pub fn initial_response(
+        &self,
+        user: &str,
+        host: &str,
+        port: u16,
+        token: &Password,
+    ) -> Password {
+        let token = token.expose();
+        Password::from(match self.config.mechanism {
+            Mechanism::XOAuth2 => format!("user={}\x01auth=Bearer {}\x01\x01", user, token),
+            Mechanism::OAuthBearer => format!(
+                "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+                user, host, port, token
+            ),
+        })
+    }
+
+    async fn refresh(&self) -> Result<Token, CredentialError> {
+        debug!(url = %self.config.token_url, "refreshing access token");
+        let mut params = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", self.refresh_token.expose()),
+            ("client_id", &self.config.client_id),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            params.push(("client_secret", secret.expose()));
+        }
+        let body = serde_urlencoded::to_string(&params).map_err(oauth2_error)?;
+
+        let req = Request::post(&self.config.token_url)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .map_err(oauth2_error)?;
+        let client = hyper::Client::builder().build::<_, Body>(HttpsConnector::new());
+        let rsp = client.request(req).await.map_err(oauth2_error)?;
+        let status = rsp.status();
+        let bytes = hyper::body::to_bytes(rsp.into_body())
+            .await
+            .map_err(oauth2_error)?;
+        if !status.is_success() {
+            return Err(CredentialError::OAuth2(format!(
+                "token endpoint returned {}: {}",
+                status,
+                String::from_utf8_lossy(&bytes)
+            )));
+        }
+
+        let rsp: TokenResponse = serde_json::from_slice(&bytes).map_err(oauth2_error)?;
+        Ok(Token {
+            access_token: rsp.access_token,
+            expires_at: Utc::now().timestamp() + rsp.expires_in.unwrap_or(DEFAULT_EXPIRY),
+        })
+    }
+
+    fn read_cache(&self) -> Option<Token> {
+        let path = self.config.token_cache.as_ref()?;
+        let data = fs::read(path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(token) => Some(token),
+            Err(e) => {
+                warn!(?path, error = %e, "ignoring invalid token cache");
+                None
+            }
+        }
+    }
+
+    fn write_cache(&self, token: &Token) {
+        let path = match &self.config.token_cache {
+            Some(path) => path,
+            None => return,
+        };
+
+        let data = serde_json::json!({
+            "access_token": token.access_token.expose(),
+            "expires_at": token.expires_at,
+        });
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let result = options
+            .open(path)
+            .and_then(|mut file| file.write_all(data.to_string().as_bytes()));
+        if let Err(e) = result {
+            warn!(?path, error = %e, "failed to write token cache");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: Password,
+    /// Unix timestamp
+    expires_at: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Password,
+    expires_in: Option<i64>,
+}
+
+fn oauth2_error<E: std::fmt::Display>(e: E) -> CredentialError {
+    CredentialError::OAuth2(e.to_string())
+}
+
+/// Refresh tokens this many seconds before they expire
+const EXPIRY_MARGIN: i64 = 60;
+/// Lifetime to assume if the token endpoint doesn't specify one
+const DEFAULT_EXPIRY: i64 = 3600;
@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use postgres::Client;
+
+/// Move a message (and its label links) out of `messages` into the `quarantine` table
+///
+/// Returns `false` if no message with the given id exists.
+pub fn quarantine(conn: &mut Client, id: i32, reason: &str) -> Result<bool, postgres::Error> {
+    // All parts of the statement see the same snapshot, so the label links can still be
+    // collected while the cascading delete removes them.
+    let moved = conn.execute(
+        "WITH moved AS (DELETE FROM messages WHERE id = $1 RETURNING *) \
+         INSERT INTO quarantine (id, unid, mod_seq, dt, subject, mid, bytes, flags, thrid, \
+                                 gm_msgid, headers, size, parts, labels, reason) \
+         SELECT id, unid, mod_seq, dt, subject, mid, bytes, flags, thrid, gm_msgid, \
+                headers, size, parts, \
+                ARRAY(SELECT label FROM message_labels WHERE message = moved.id), $2 \
+         FROM moved",
+        &[&id, &reason],
+    )?;
+    Ok(moved > 0)
+}
+
+/// Move quarantined messages back into `messages`, restoring their labels
+///
+/// If `ids` is `None`, all quarantined messages are restored. Messages that conflict
+/// with one in `messages` (by id or Gmail message ID) stay in quarantine.
+pub fn restore(conn: &mut Client, ids: Option<&[i32]>) -> Result<Restored, postgres::Error> {
+    let mut tx = conn.transaction()?;
+    let restored = tx.execute(
+        "WITH inserted AS ( \
+             INSERT INTO messages (id, unid, mod_seq, dt, subject, mid, bytes, flags, thrid, \
+                                   gm_msgid, headers, size, parts) \
+             SELECT id, unid, mod_seq, dt, subject, mid, bytes, flags, thrid, gm_msgid, \
+                    headers, size, parts \
+             FROM quarantine WHERE $1::INTEGER[] IS NULL OR id = ANY($1) \
+             ON CONFLICT DO NOTHING \
+             RETURNING id \
+         ), links AS ( \
+             INSERT INTO message_labels (message, label) \
+             SELECT q.id, l.label \
+             FROM quarantine q JOIN inserted USING (id), unnest(q.labels) AS l (label) \
+             WHERE l.label IN (SELECT id FROM labels) \
+         ), removed AS ( \
+             DELETE FROM quarantine WHERE id IN (SELECT id FROM inserted) \
+         ) \
+         SELECT id FROM inserted",
+        &[&ids],
+    )?;
+    let skipped = tx
+        .query(
+            "SELECT id FROM quarantine WHERE $1::INTEGER[] IS NULL OR id = ANY($1) ORDER BY id",
+            &[&ids],
+        )?
+        .into_iter()
+        .map(|row| row.get(0))
+        .collect();
+    tx.commit()?;
+    Ok(Restored { restored, skipped })
+}
+
+/// Outcome of `restore()`
+pub struct Restored {
+    pub restored: u64,
+    /// Ids of messages left in quarantine, because they are already in `messages`
+    pub skipped: Vec<i32>,
+}
+
+/// List quarantined messages, oldest first
+pub fn list(conn: &mut Client) -> Result<Vec<Quarantined>, postgres::Error> {
+    let rows = conn.query(
+        "SELECT id, mid, subject, reason, quarantined FROM quarantine ORDER BY quarantined, id",
+        &[],
+    )?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Quarantined {
+            id: row.get(0),
+            mid: row.get(1),
+            subject: row.get(2),
+            reason: row.get(3),
+            quarantined: row.get(4),
+        })
+        .collect())
+}
+
+pub struct Quarantined {
+    pub id: i32,
+    pub mid: Option<String>,
+    pub subject: Option<String>,
+    pub reason: Option<String>,
+    pub quarantined: DateTime<Utc>,
+}
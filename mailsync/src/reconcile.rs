@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset};
+use email_parser::Message;
+use postgres::Client;
+use ring::digest;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, debug_span, info};
+
+use crate::accumulate::Fetched;
+use crate::meta::{self, MessageMeta, MetaStore};
+use crate::{fuzzy_datetime_parser, logging, login, Config, SyncError};
+
+/// Metadata for a message on the IMAP server, as written by `get-meta --csv`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RemoteMeta {
+    pub seq: u32,
+    pub uid: u32,
+    pub mod_seq: u64,
+    pub mid: Option<String>,
+    pub date: Option<String>,
+    pub subject: Option<String>,
+    pub sender: Option<String>,
+    #[serde(default)]
+    pub size: Option<u32>,
+    /// Hex-encoded SHA-256 of the full message source
+    #[serde(default)]
+    pub hash: Option<String>,
+    #[serde(default)]
+    pub gm_msgid: Option<u64>,
+    #[serde(default)]
+    pub gm_thrid: Option<u64>,
+}
+
+impl From<&MessageMeta> for RemoteMeta {
+    fn from(meta: &MessageMeta) -> Self {
+        Self {
+            seq: meta.seq,
+            uid: meta.uid,
+            mod_seq: meta.mod_seq,
+            mid: meta.mid.clone(),
+            date: meta.dt.map(|dt| dt.to_rfc2822()),
+            subject: meta.subject.clone(),
+            sender: meta.sender.clone(),
+            size: None,
+            hash: None,
+            gm_msgid: meta.gm_msgid,
+            gm_thrid: meta.gm_thrid,
+        }
+    }
+}
+
+pub fn read_csv(fname: &str) -> Result<Vec<RemoteMeta>, csv::Error> {
+    let mut reader = csv::Reader::from_path(fname)?;
+    reader.deserialize().collect()
+}
+
+pub fn write_csv(fname: &str, metas: &[RemoteMeta]) -> Result<(), csv::Error> {
+    let mut writer = csv::Writer::from_path(fname)?;
+    for meta in metas {
+        writer.serialize(meta)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Where to get remote metadata from: `store`, `imap` or `csv:` and the path to a CSV file
+///
+/// The prefix keeps files named `store` or `imap` from being mistaken for the keywords.
+#[derive(Debug)]
+pub enum Source {
+    Csv(String),
+    /// The INBOX metadata as last written by `get-meta`
+    Store,
+    /// Fetch envelopes for INBOX from the IMAP server
+    Imap,
+}
+
+impl Source {
+    pub fn load(&self, config: &Config) -> Result<Vec<RemoteMeta>, SyncError> {
+        match self {
+            Source::Csv(fname) => Ok(read_csv(fname)?),
+            Source::Store => {
+                let mut rt = tokio::runtime::Runtime::new()?;
+                rt.block_on(async {
+                    let (db, connection) =
+                        tokio_postgres::connect(&config.store.uri, tokio_postgres::NoTls).await?;
+                    tokio::spawn(connection);
+                    let metas = MetaStore::new(&db).all().await?;
+                    Ok(metas.iter().map(RemoteMeta::from).collect())
+                })
+            }
+            Source::Imap => {
+                let auth = config.imap.auth()?;
+                let mut rt = tokio::runtime::Runtime::new()?;
+                rt.block_on(async {
+                    let mut session = login(&config.imap, &auth).await?;
+                    let mut remote = Vec::new();
+                    if meta::examine(&mut session).await?.exists > 0 {
+                        // Flags don't matter for matching, so updates are ignored
+                        meta::fetch_envelopes(&mut session, None, |fetched| {
+                            if let Fetched::Message(meta) = fetched {
+                                remote.push(RemoteMeta::from(&meta));
+                            }
+                            Ok(())
+                        })
+                        .await?;
+                    }
+                    Ok(remote)
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match (s, s.strip_prefix("csv:")) {
+            ("store", _) => Source::Store,
+            ("imap", _) => Source::Imap,
+            (_, Some(path)) if !path.is_empty() => Source::Csv(path.into()),
+            _ => {
+                return Err(format!(
+                    "unknown metadata source {:?}, expected `store`, `imap` or `csv:PATH`",
+                    s
+                ))
+            }
+        })
+    }
+}
+
+/// A message from the local store, with the fields matchers look at
+pub struct LocalMessage {
+    pub id: i32,
+    /// Gmail's message ID, for messages imported from Takeout
+    pub gm_msgid: Option<u64>,
+    pub mid: Option<String>,
+    pub subject: Option<String>,
+    pub date: Option<DateTime<FixedOffset>>,
+    pub sender: Option<String>,
+    pub size: usize,
+    /// SHA-256 of the source, only computed if the `hash` matcher is used
+    pub hash: Option<String>,
+}
+
+impl LocalMessage {
+    pub fn new(
+        id: i32,
+        gm_msgid: Option<u64>,
+        mid: Option<String>,
+        subject: Option<String>,
+        bytes: &[u8],
+        hash: bool,
+    ) -> Self {
+        let msg = Message::from_slice(bytes);
+        let headers = msg.headers();
+        let date = headers
+            .get_first("date")
+            .and_then(|s| fuzzy_datetime_parser(&s));
+        let sender = headers
+            .get_first("sender")
+            .or_else(|| headers.get_first("from"))
+            .map(|s| s.to_string());
+
+        Self {
+            id,
+            gm_msgid,
+            mid,
+            subject,
+            date,
+            sender,
+            size: bytes.len(),
+            hash: match hash {
+                true => Some(hex(digest::digest(&digest::SHA256, bytes).as_ref())),
+                false => None,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Matcher {
+    /// Gmail's X-GM-MSGID, which is always applied first
+    GmailId,
+    MessageId,
+    Date,
+    Subject,
+    Sender,
+    Size,
+    Hash,
+}
+
+impl Matcher {
+    /// Whether the local and remote message agree on this matcher's field
+    ///
+    /// Returns `None` if the field is missing on either side, so that matchers
+    /// can't rule out candidates based on absent data (except where both sides
+    /// agree it is absent).
+    fn check(self, local: &LocalMessage, remote: &RemoteMeta) -> Option<bool> {
+        match self {
+            Matcher::GmailId => match (local.gm_msgid, remote.gm_msgid) {
+                (Some(l), Some(r)) => Some(l == r),
+                _ => None,
+            },
+            Matcher::MessageId => match (&local.mid, &remote.mid) {
+                (Some(l), Some(r)) => Some(l.trim() == r.trim()),
+                _ => None,
+            },
+            Matcher::Date => match (&local.date, remote.date()) {
+                (Some(l), Some(r)) => Some(*l == r),
+                _ => None,
+            },
+            Matcher::Subject => match (&local.subject, &remote.subject) {
+                // IMAP servers truncate long subjects in the envelope
+                (Some(l), Some(r)) if r.len() == 998 => Some(l.get(..998) == Some(r)),
+                (Some(l), Some(r)) => Some(l == r),
+                (None, None) => Some(true),
+                _ => Some(false),
+            },
+            Matcher::Sender => match (&local.sender, &remote.sender) {
+                (Some(l), Some(r)) => Some(sender_address(l) == sender_address(r)),
+                (None, None) => Some(true),
+                _ => Some(false),
+            },
+            Matcher::Size => remote.size.map(|size| size as usize == local.size),
+            Matcher::Hash => match (&local.hash, &remote.hash) {
+                (Some(l), Some(r)) => Some(l.eq_ignore_ascii_case(r)),
+                _ => None,
+            },
+        }
+    }
+
+    fn weight(self) -> u32 {
+        match self {
+            Matcher::GmailId => 16,
+            Matcher::Hash => 8,
+            Matcher::MessageId => 4,
+            Matcher::Date => 2,
+            Matcher::Subject | Matcher::Sender | Matcher::Size => 1,
+        }
+    }
+}
+
+impl FromStr for Matcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "gmid" => Matcher::GmailId,
+            "mid" => Matcher::MessageId,
+            "date" => Matcher::Date,
+            "subject" => Matcher::Subject,
+            "sender" => Matcher::Sender,
+            "size" => Matcher::Size,
+            "hash" => Matcher::Hash,
+            _ => return Err(format!("unknown matcher {:?}", s)),
+        })
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Matcher::GmailId => "gmid",
+            Matcher::MessageId => "mid",
+            Matcher::Date => "date",
+            Matcher::Subject => "subject",
+            Matcher::Sender => "sender",
+            Matcher::Size => "size",
+            Matcher::Hash => "hash",
+        })
+    }
+}
+
+/// Parse a comma-separated list of matchers, like `mid,subject,sender`
+pub fn parse_cascade(s: &str) -> Result<Vec<Matcher>, String> {
+    s.split(',').map(|m| m.trim().parse()).collect()
+}
+
+pub enum Outcome<'a> {
+    Matched {
+        remote: &'a RemoteMeta,
+        score: u32,
+    },
+    /// Several candidates remain with the same best score
+    Ambiguous(Vec<&'a RemoteMeta>),
+    /// No remote message shares an indexed field (Message-ID or date)
+    NoCandidates,
+    /// All candidates were ruled out by the given matcher
+    Filtered(Matcher),
+}
+
+/// Matches local messages against remote metadata by running a cascade of matchers
+///
+/// Candidates are found through the Gmail message ID index and the Message-ID and date
+/// indices (for those matchers that are part of the cascade), then each matcher in turn
+/// removes candidates that disagree with the local message and adds to the score of
+/// those that agree. Gmail message IDs are unique, so they are always checked first.
+pub struct Engine {
+    cascade: Vec<Matcher>,
+    remote: Vec<RemoteMeta>,
+    by_gmid: HashMap<u64, Vec<usize>>,
+    by_mid: HashMap<String, Vec<usize>>,
+    by_date: HashMap<DateTime<FixedOffset>, Vec<usize>>,
+}
+
+impl Engine {
+    pub fn new(mut cascade: Vec<Matcher>, remote: Vec<RemoteMeta>) -> Result<Self, String> {
+        let indexed = cascade
+            .iter()
+            .any(|m| *m == Matcher::MessageId || *m == Matcher::Date);
+        if !indexed {
+            return Err("cascade must contain the mid or date matcher".into());
+        }
+        cascade.retain(|m| *m != Matcher::GmailId);
+        cascade.insert(0, Matcher::GmailId);
+
+        let mut by_gmid = HashMap::new();
+        let mut by_mid = HashMap::new();
+        let mut by_date = HashMap::new();
+        for (i, meta) in remote.iter().enumerate() {
+            if let Some(gmid) = meta.gm_msgid {
+                by_gmid.entry(gmid).or_insert_with(Vec::new).push(i);
+            }
+            if cascade.contains(&Matcher::MessageId) {
+                if let Some(mid) = &meta.mid {
+                    by_mid
+                        .entry(mid.trim().to_string())
+                        .or_insert_with(Vec::new)
+                        .push(i);
+                }
+            }
+            if cascade.contains(&Matcher::Date) {
+                if let Some(dt) = meta.date() {
+                    by_date.entry(dt).or_insert_with(Vec::new).push(i);
+                }
+            }
+        }
+
+        Ok(Self {
+            cascade,
+            remote,
+            by_gmid,
+            by_mid,
+            by_date,
+        })
+    }
+
+    pub fn remote(&self) -> &[RemoteMeta] {
+        &self.remote
+    }
+
+    /// Whether local messages need a hash of their source, which is costly to compute
+    pub fn hashes(&self) -> bool {
+        self.cascade.contains(&Matcher::Hash)
+    }
+
+    /// Whether any remote message has the given Message-ID
+    pub fn has_mid(&self, mid: &str) -> bool {
+        self.by_mid.contains_key(mid.trim())
+    }
+
+    pub fn reconcile(&self, local: &LocalMessage) -> Outcome<'_> {
+        let mut candidates = Vec::new();
+        if let Some(idxs) = local.gm_msgid.and_then(|gmid| self.by_gmid.get(&gmid)) {
+            candidates.extend(idxs.iter().map(|&i| (i, 0)));
+        }
+        if let Some(idxs) = local
+            .mid
+            .as_ref()
+            .and_then(|mid| self.by_mid.get(mid.trim()))
+        {
+            for &i in idxs {
+                if !candidates.iter().any(|&(c, _)| c == i) {
+                    candidates.push((i, 0));
+                }
+            }
+        }
+        if let Some(idxs) = local.date.as_ref().and_then(|dt| self.by_date.get(dt)) {
+            for &i in idxs {
+                if !candidates.iter().any(|&(c, _)| c == i) {
+                    candidates.push((i, 0));
+                }
+            }
+        }
+
+        if candidates.is_empty() {
+            return Outcome::NoCandidates;
+        }
+
+        for &matcher in &self.cascade {
+            candidates = candidates
+                .into_iter()
+                .filter_map(|(i, score)| match matcher.check(local, &self.remote[i]) {
+                    Some(true) => Some((i, score + matcher.weight())),
+                    Some(false) => None,
+                    None => Some((i, score)),
+                })
+                .collect();
+
+            if candidates.is_empty() {
+                return Outcome::Filtered(matcher);
+            }
+        }
+
+        let best = candidates.iter().map(|&(_, score)| score).max().unwrap();
+        let top = candidates
+            .iter()
+            .filter(|&&(_, score)| score == best)
+            .map(|&(i, _)| &self.remote[i])
+            .collect::<Vec<_>>();
+        if top.len() == 1 {
+            Outcome::Matched {
+                remote: top[0],
+                score: best,
+            }
+        } else {
+            Outcome::Ambiguous(top)
+        }
+    }
+}
+
+impl RemoteMeta {
+    fn date(&self) -> Option<DateTime<FixedOffset>> {
+        self.date.as_ref().and_then(|s| fuzzy_datetime_parser(s))
+    }
+}
+
+/// Reconcile all local messages without a UID, storing UID and mod-seq for matches
+///
+/// With `dry_run`, only the report is produced.
+pub fn run(engine: &Engine, conn: &mut Client, dry_run: bool) -> Result<Report, postgres::Error> {
+    let mut report = Report::default();
+    let mut tx = conn.transaction()?;
+    let total: i64 = tx
+        .query_one("SELECT COUNT(*) FROM messages WHERE unid IS NULL", &[])?
+        .get(0);
+    info!(total, dry_run, "reconciling messages without UID");
+
+    let update = tx.prepare("UPDATE messages SET unid = $1, mod_seq = $2 WHERE id = $3")?;
+    let portal = tx.bind(
+        "SELECT id, mid, subject, bytes, gm_msgid FROM messages WHERE unid IS NULL \
+         ORDER BY id ASC",
+        &[],
+    )?;
+
+    let bar = logging::progress(Some(total as u64), "messages");
+    let mut offset = 0;
+    loop {
+        let rows = tx.query_portal(&portal, BATCH_SIZE)?;
+        if rows.is_empty() {
+            break;
+        }
+
+        let span = debug_span!("batch", offset);
+        let _enter = span.enter();
+        offset += rows.len();
+        for row in rows {
+            bar.inc(1);
+
+            // Chat logs are stored like messages in Gmail, but never show up over IMAP
+            let mid: Option<String> = row.get(1);
+            if let Some(true) = mid.as_ref().map(|m| m.ends_with("chat@gmail.com>")) {
+                continue;
+            }
+
+            let bytes: Vec<u8> = row.get(3);
+            let gm_msgid: Option<i64> = row.get(4);
+            let gm_msgid = gm_msgid.map(|id| id as u64);
+            let local = LocalMessage::new(
+                row.get(0),
+                gm_msgid,
+                mid,
+                row.get(2),
+                &bytes,
+                engine.hashes(),
+            );
+            let outcome = engine.reconcile(&local);
+            report.record(&local, &outcome);
+            if let Outcome::Matched { remote, score } = outcome {
+                debug!(id = local.id, uid = remote.uid, score, "matched");
+                if !dry_run {
+                    let (uid, mod_seq) = (remote.uid as i64, remote.mod_seq as i64);
+                    tx.execute(&update, &[&uid, &mod_seq, &local.id])?;
+                    report.updated += 1;
+                }
+            }
+        }
+    }
+
+    bar.finish_and_clear();
+    tx.commit()?;
+    Ok(report)
+}
+
+/// Tallies outcomes and collects the ones that need review
+#[derive(Default)]
+pub struct Report {
+    pub matched: usize,
+    pub updated: usize,
+    /// Local messages (id and Message-ID) without any candidates
+    pub no_candidates: Vec<(i32, Option<String>)>,
+    pub filtered: HashMap<String, usize>,
+    /// Local message id with the UIDs of the remaining candidates
+    pub ambiguous: Vec<(i32, Vec<u32>)>,
+}
+
+impl Report {
+    pub fn record(&mut self, local: &LocalMessage, outcome: &Outcome<'_>) {
+        match outcome {
+            Outcome::Matched { .. } => self.matched += 1,
+            Outcome::Ambiguous(candidates) => self
+                .ambiguous
+                .push((local.id, candidates.iter().map(|m| m.uid).collect())),
+            Outcome::NoCandidates => self.no_candidates.push((local.id, local.mid.clone())),
+            Outcome::Filtered(matcher) => {
+                *self.filtered.entry(matcher.to_string()).or_insert(0) += 1
+            }
+        }
+    }
+
+    /// Write the ambiguous matches as CSV, one candidate UID per row
+    pub fn write_review(&self, fname: &str) -> Result<(), csv::Error> {
+        let mut writer = csv::Writer::from_path(fname)?;
+        writer.write_record(&["id", "uid"])?;
+        for (id, uids) in &self.ambiguous {
+            for uid in uids {
+                writer.write_record(&[id.to_string(), uid.to_string()])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "matched: {} (updated {})", self.matched, self.updated)?;
+        writeln!(f, "ambiguous: {}", self.ambiguous.len())?;
+        writeln!(f, "no candidates: {}", self.no_candidates.len())?;
+        for (matcher, count) in &self.filtered {
+            writeln!(f, "ruled out by {}: {}", matcher, count)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn sender_address(s: &str) -> &str {
+    let start = match s.find('<') {
+        Some(s) => s,
+        None => {
+            return s.trim();
+        }
+    };
+    let started = &s[start + 1..];
+    match started.find('>') {
+        Some(end) => &started[..end],
+        None => started.trim(),
+    }
+}
+
+const BATCH_SIZE: i32 = 1000;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,120 @@
+/// Schema changes on top of the original `messages` and `labels` tables, in order
+///
+/// Every statement must be idempotent, so that they can all be applied on each run.
+pub const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE messages ADD COLUMN IF NOT EXISTS thrid BIGINT",
+    "CREATE UNIQUE INDEX IF NOT EXISTS labels_name ON labels (name)",
+    "CREATE TABLE IF NOT EXISTS message_labels (
+        message INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        label INTEGER NOT NULL REFERENCES labels (id) ON DELETE CASCADE,
+        PRIMARY KEY (message, label)
+    )",
+    "CREATE TABLE IF NOT EXISTS quarantine (LIKE messages)",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS labels INTEGER[] NOT NULL DEFAULT '{}'",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS reason TEXT",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS quarantined TIMESTAMPTZ NOT NULL DEFAULT now()",
+    "CREATE UNIQUE INDEX IF NOT EXISTS quarantine_id ON quarantine (id)",
+    "CREATE TABLE IF NOT EXISTS sync_status (
+        folder TEXT PRIMARY KEY,
+        last_success TIMESTAMPTZ NOT NULL,
+        uid_next BIGINT,
+        max_uid BIGINT
+    )",
+    "ALTER TABLE messages ADD COLUMN IF NOT EXISTS gm_msgid BIGINT",
+    "CREATE UNIQUE INDEX IF NOT EXISTS messages_gm_msgid ON messages (gm_msgid)",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS gm_msgid BIGINT",
+    "ALTER TABLE messages ALTER COLUMN bytes DROP NOT NULL",
+    "ALTER TABLE messages ADD COLUMN IF NOT EXISTS headers BYTEA",
+    "ALTER TABLE messages ADD COLUMN IF NOT EXISTS size INTEGER",
+    "ALTER TABLE messages ADD COLUMN IF NOT EXISTS parts TEXT[]",
+    "ALTER TABLE quarantine ALTER COLUMN bytes DROP NOT NULL",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS headers BYTEA",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS size INTEGER",
+    "ALTER TABLE quarantine ADD COLUMN IF NOT EXISTS parts TEXT[]",
+    "ALTER TABLE sync_status ALTER COLUMN last_success DROP NOT NULL",
+    "ALTER TABLE sync_status ADD COLUMN IF NOT EXISTS checkpoint BIGINT",
+    "CREATE TABLE IF NOT EXISTS inbox (
+        uid BIGINT PRIMARY KEY,
+        seq BIGINT NOT NULL,
+        mod_seq BIGINT NOT NULL,
+        flags flags[] NOT NULL,
+        labels TEXT[] NOT NULL,
+        gm_msgid BIGINT,
+        gm_thrid BIGINT,
+        mid TEXT,
+        dt TIMESTAMPTZ,
+        subject TEXT,
+        sender TEXT
+    )",
+    "CREATE TABLE IF NOT EXISTS inbox_state (
+        mailbox TEXT PRIMARY KEY,
+        uid_validity BIGINT NOT NULL,
+        highest_mod_seq BIGINT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS inbox_pending (
+        id BIGSERIAL PRIMARY KEY,
+        uid BIGINT NOT NULL,
+        action TEXT NOT NULL,
+        argument TEXT NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS web_users (
+        name TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL,
+        accounts TEXT[]
+    )",
+    "CREATE OR REPLACE FUNCTION inbox_notify() RETURNS trigger AS $$
+    BEGIN
+        CASE TG_OP
+            WHEN 'INSERT' THEN PERFORM pg_notify('inbox', 'message ' || NEW.uid);
+            WHEN 'UPDATE' THEN PERFORM pg_notify('inbox', 'flags ' || NEW.uid);
+            WHEN 'DELETE' THEN PERFORM pg_notify('inbox', 'removed ' || OLD.uid);
+        END CASE;
+        RETURN NULL;
+    END
+    $$ LANGUAGE plpgsql",
+    "DO $$ BEGIN
+        IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'inbox_changed') THEN
+            CREATE TRIGGER inbox_changed AFTER INSERT OR DELETE ON inbox
+                FOR EACH ROW EXECUTE PROCEDURE inbox_notify();
+        END IF;
+        IF NOT EXISTS (SELECT 1 FROM pg_trigger WHERE tgname = 'inbox_flags_changed') THEN
+            CREATE TRIGGER inbox_flags_changed AFTER UPDATE ON inbox
+                FOR EACH ROW WHEN (OLD.flags IS DISTINCT FROM NEW.flags)
+                EXECUTE PROCEDURE inbox_notify();
+        END IF;
+    END $$",
+    "ALTER TABLE inbox_state ALTER COLUMN highest_mod_seq DROP NOT NULL",
+    "CREATE TABLE IF NOT EXISTS web_sessions (
+        name TEXT PRIMARY KEY,
+        generation BIGINT NOT NULL
+    )",
+];
+
+pub fn migrate(conn: &mut postgres::Client) -> Result<(), postgres::Error> {
+    for statement in MIGRATIONS {
+        conn.batch_execute(statement)?;
+    }
+    Ok(())
+}
+
+/// Like `migrate()`, for async connections
+pub async fn migrate_async(conn: &tokio_postgres::Client) -> Result<(), tokio_postgres::Error> {
+    for statement in MIGRATIONS {
+        conn.batch_execute(*statement).await?;
+    }
+    Ok(())
+}
+
+/// Connect to the store, logging errors on the connection instead of panicking
+///
+/// Once the connection fails, queries on the client return errors, so long-running callers
+/// should connect again rather than keep using it.
+pub async fn connect(uri: &str) -> Result<tokio_postgres::Client, tokio_postgres::Error> {
+    let (db, connection) = tokio_postgres::connect(uri, tokio_postgres::NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::error!(error = %e, "database connection error");
+        }
+    });
+    Ok(db)
+}
@@ -0,0 +1,272 @@
+use chrono::{DateTime, FixedOffset};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::Body;
+use mailsync::meta::{Filter, MessageMeta};
+use mendes::handler;
+use mendes::http::{request::Parts, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{normalize_subject, App, Error, Response};
+
+#[handler(App)]
+pub async fn api(app: &App, req: &Parts) -> Result<Response, Error> {
+    match route(app, req).await {
+        Ok(rsp) => Ok(rsp),
+        Err(e) => {
+            // Details of internal errors are logged by `status()`, not sent to clients
+            let status = e.status();
+            let message = match status.is_server_error() {
+                true => "internal server error".to_string(),
+                false => e.to_string(),
+            };
+            json(
+                status,
+                &ErrorBody {
+                    error: ErrorDetail {
+                        status: status.as_u16(),
+                        message,
+                    },
+                },
+            )
+        }
+    }
+}
+
+async fn route(app: &App, req: &Parts) -> Result<Response, Error> {
+    let session = app.auth.api_session(&app.db, req).await?;
+    if !session.user.can_access(&app.account) {
+        return Err(Error::Forbidden);
+    }
+
+    if req.method != Method::GET {
+        return Err(Error::MethodNotAllowed);
+    }
+
+    let path = req.uri.path().trim_end_matches('/');
+    let segments = match path.strip_prefix(PREFIX) {
+        Some(rest) => rest.split('/').collect::<Vec<_>>(),
+        None => return Err(Error::NotFound),
+    };
+
+    let query = req.uri.query().unwrap_or("");
+    let query = serde_urlencoded::from_str::<Query>(query).map_err(|_| Error::BadRequest)?;
+    match segments.as_slice() {
+        ["messages"] | ["search"] => messages(app, &query).await,
+        ["messages", uid] => {
+            let meta = app.store().get(parse_uid(uid)?).await?;
+            let meta = meta.ok_or(Error::NotFound)?;
+            json(StatusCode::OK, &meta)
+        }
+        ["messages", uid, "raw"] => raw(app, parse_uid(uid)?).await,
+        ["threads"] => threads(app, &query).await,
+        ["labels"] => labels(app).await,
+        _ => Err(Error::NotFound),
+    }
+}
+
+async fn messages(app: &App, query: &Query) -> Result<Response, Error> {
+    let limit = query.limit(app.config.page_size);
+    let end = match &query.page_token {
+        Some(token) => decode_token(token)?,
+        None => u32::MAX,
+    };
+
+    // Ask for one more than fits, to find out if there's a next page
+    let mut messages = app.store().search(&query.filter(), end, limit + 1).await?;
+    let mut next_page_token = None;
+    if messages.len() > limit {
+        messages.truncate(limit);
+        next_page_token = messages.last().map(|m| encode_token(m.uid));
+    }
+
+    json(
+        StatusCode::OK,
+        &MessageList {
+            messages,
+            next_page_token,
+        },
+    )
+}
+
+async fn raw(app: &App, uid: u32) -> Result<Response, Error> {
+    let meta = app.store().get(uid).await?.ok_or(Error::NotFound)?;
+    // The UID is from the INBOX, while `messages.unid` is from the sync folder, so the
+    // Gmail message ID is the reliable link; Message-IDs may be missing or reused
+    let row = match (meta.gm_msgid, &meta.mid) {
+        (Some(gm_msgid), _) => {
+            app.db
+                .query_opt(
+                    "SELECT id, unid, bytes FROM messages WHERE gm_msgid = $1",
+                    &[&(gm_msgid as i64)],
+                )
+                .await?
+        }
+        (None, Some(mid)) => {
+            app.db
+                .query_opt(
+                    "SELECT id, unid, bytes FROM messages WHERE mid = $1 LIMIT 1",
+                    &[mid],
+                )
+                .await?
+        }
+        (None, None) => None,
+    };
+    let row = row.ok_or(Error::NotFound)?;
+
+    let bytes = match row.get(2) {
+        Some(bytes) => bytes,
+        // Synced with headers only, so download the body now
+        None => {
+            let unid: Option<i64> = row.get(1);
+            let unid = unid.ok_or(Error::NotFound)?;
+            app.fill_body(row.get(0), unid as u32)
+                .await?
+                .ok_or(Error::NotFound)?
+        }
+    };
+    Ok(hyper::Response::builder()
+        .header(CONTENT_TYPE, "message/rfc822")
+        .header(CONTENT_LENGTH, bytes.len())
+        .body(bytes.into())?)
+}
+
+async fn threads(app: &App, query: &Query) -> Result<Response, Error> {
+    let limit = query.limit(app.config.page_size);
+    let end = match &query.page_token {
+        Some(token) => decode_token(token)?,
+        None => u32::MAX,
+    };
+
+    // Threads are paged by their newest message, like messages by their own UID
+    let mut threads = app.store().threads(&query.filter(), end, limit + 1).await?;
+    let mut next_page_token = None;
+    if threads.len() > limit {
+        threads.truncate(limit);
+        next_page_token = threads.last().map(|t| encode_token(t.uid));
+    }
+
+    let threads = threads
+        .into_iter()
+        .map(|thread| Thread {
+            subject: normalize_subject(thread.subject.as_deref().unwrap_or("")).to_string(),
+            latest: thread.latest,
+            unread: thread.unread,
+            messages: thread.messages,
+        })
+        .collect();
+    json(
+        StatusCode::OK,
+        &ThreadList {
+            threads,
+            next_page_token,
+        },
+    )
+}
+
+async fn labels(app: &App) -> Result<Response, Error> {
+    let labels = app
+        .store()
+        .labels()
+        .await?
+        .into_iter()
+        .map(|(name, messages)| Label { name, messages })
+        .collect();
+    json(StatusCode::OK, &LabelList { labels })
+}
+
+fn json<T: Serialize>(status: StatusCode, data: &T) -> Result<Response, Error> {
+    let content = serde_json::to_vec(data).map_err(Error::Json)?;
+    Ok(hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, content.len())
+        .body(Body::from(content))?)
+}
+
+fn parse_uid(s: &str) -> Result<u32, Error> {
+    s.parse().map_err(|_| Error::NotFound)
+}
+
+fn encode_token(uid: u32) -> String {
+    base64::encode_config(&uid.to_be_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_token(token: &str) -> Result<u32, Error> {
+    let bytes = base64::decode_config(token, base64::URL_SAFE_NO_PAD);
+    match bytes {
+        Ok(bytes) if bytes.len() == 4 => {
+            Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        }
+        _ => Err(Error::BadRequest),
+    }
+}
+
+#[derive(Deserialize)]
+struct Query {
+    /// Case-insensitive substring to look for in subject and sender
+    q: Option<String>,
+    unread: Option<bool>,
+    flagged: Option<bool>,
+    label: Option<String>,
+    limit: Option<usize>,
+    page_token: Option<String>,
+}
+
+impl Query {
+    fn filter(&self) -> Filter {
+        Filter {
+            unread: self.unread,
+            flagged: self.flagged,
+            label: self.label.clone(),
+            text: self.q.clone(),
+        }
+    }
+
+    fn limit(&self, page_size: usize) -> usize {
+        self.limit.unwrap_or(page_size).min(page_size).max(1)
+    }
+}
+
+#[derive(Serialize)]
+struct MessageList {
+    messages: Vec<MessageMeta>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Thread {
+    subject: String,
+    latest: Option<DateTime<FixedOffset>>,
+    unread: usize,
+    messages: Vec<u32>,
+}
+
+#[derive(Serialize)]
+struct ThreadList {
+    threads: Vec<Thread>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Label {
+    name: String,
+    messages: usize,
+}
+
+#[derive(Serialize)]
+struct LabelList {
+    labels: Vec<Label>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    status: u16,
+    message: String,
+}
+
+const PREFIX: &str = "/api/v1/";
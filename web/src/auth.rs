@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::str;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{AUTHORIZATION, COOKIE};
+use mailsync::{UserConfig, WebConfig};
+use mendes::http::request::Parts;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{digest, hmac, pbkdf2};
+use tokio_postgres::Client;
+
+use crate::Error;
+
+/// Checks credentials against the configured users and those in the `web_users` table
+pub struct Auth {
+    key: hmac::Key,
+    users: Vec<UserConfig>,
+    secure: bool,
+    /// Recently verified Basic credentials, so API clients don't pay for PBKDF2 on every request
+    verified: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl Auth {
+    pub fn new(config: &WebConfig) -> Result<Self, Error> {
+        let key = match &config.session_key {
+            Some(encoded) => base64::decode(encoded).map_err(|_| Error::Config("session_key"))?,
+            None => {
+                let mut key = vec![0; digest::SHA256_OUTPUT_LEN];
+                SystemRandom::new()
+                    .fill(&mut key)
+                    .map_err(|_| Error::Config("session_key"))?;
+                key
+            }
+        };
+
+        Ok(Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+            users: config.users.clone(),
+            secure: config.secure_cookies,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check the credentials and return a session cookie if they match
+    pub async fn login(
+        &self,
+        db: &Client,
+        name: &str,
+        password: &str,
+    ) -> Result<Option<String>, Error> {
+        let user = match self.user(db, name).await? {
+            Some(user) => user,
+            None => {
+                // Take as long as for a wrong password, so user names can't be probed
+                verify_password(DUMMY_HASH, password);
+                return Ok(None);
+            }
+        };
+
+        if !verify_password(&user.password_hash, password) {
+            return Ok(None);
+        }
+
+        let expires = now() + SESSION_SECONDS;
+        let generation = generation(db, &user.name).await?;
+        let payload = format!("{}\n{}\n{}", user.name, expires, generation);
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        let value = format!("{}.{}", encode(payload.as_bytes()), encode(tag.as_ref()));
+        Ok(Some(self.cookie(&value, SESSION_SECONDS)))
+    }
+
+    /// End all of the user's sessions, returning a cookie that removes the session
+    /// from the client
+    pub async fn logout(&self, db: &Client, session: &Session) -> Result<String, Error> {
+        db.execute(
+            "INSERT INTO web_sessions (name, generation) VALUES ($1, 1) \
+             ON CONFLICT (name) DO UPDATE SET generation = web_sessions.generation + 1",
+            &[&session.user.name],
+        )
+        .await?;
+        Ok(self.cookie("", 0))
+    }
+
+    /// Find a valid session from the request's cookies, if any
+    pub async fn session(&self, db: &Client, req: &Parts) -> Result<Option<Session>, Error> {
+        let value = match session_cookie(req) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        let mut parts = value.splitn(2, '.');
+        let (payload, tag) = match (parts.next().map(decode), parts.next().map(decode)) {
+            (Some(Some(payload)), Some(Some(tag))) => (payload, tag),
+            _ => return Ok(None),
+        };
+
+        if hmac::verify(&self.key, &payload, &tag).is_err() {
+            return Ok(None);
+        }
+
+        let payload = match str::from_utf8(&payload) {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
+
+        let mut fields = payload.splitn(3, '\n');
+        let (name, expires, issued) = match (
+            fields.next(),
+            fields.next().map(str::parse::<u64>),
+            fields.next().map(str::parse::<i64>),
+        ) {
+            (Some(name), Some(Ok(expires)), Some(Ok(issued))) => (name, expires, issued),
+            _ => return Ok(None),
+        };
+
+        // Logging out invalidates all sessions issued before
+        if expires < now() || issued != generation(db, name).await? {
+            return Ok(None);
+        }
+
+        Ok(self.user(db, name).await?.map(|user| Session {
+            user,
+            token: value.to_string(),
+        }))
+    }
+
+    /// Like `session()`, but fails if there is no valid session
+    pub async fn require(&self, db: &Client, req: &Parts) -> Result<Session, Error> {
+        self.session(db, req).await?.ok_or(Error::Unauthorized)
+    }
+
+    /// Authenticate API clients by session cookie or HTTP Basic credentials
+    pub async fn api_session(&self, db: &Client, req: &Parts) -> Result<Session, Error> {
+        if let Some(session) = self.session(db, req).await? {
+            return Ok(session);
+        }
+
+        let header = req
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|val| val.to_str().ok())
+            .ok_or(Error::Unauthorized)?;
+        let credentials = match header.strip_prefix("Basic ") {
+            Some(encoded) => base64::decode(encoded.trim()).map_err(|_| Error::Unauthorized)?,
+            None => return Err(Error::Unauthorized),
+        };
+
+        let credentials = String::from_utf8(credentials).map_err(|_| Error::Unauthorized)?;
+        let mut split = credentials.splitn(2, ':');
+        let (name, password) = match (split.next(), split.next()) {
+            (Some(name), Some(password)) => (name, password),
+            _ => return Err(Error::Unauthorized),
+        };
+
+        let user = match self.user(db, name).await? {
+            Some(user) => user,
+            None => {
+                verify_password(DUMMY_HASH, password);
+                return Err(Error::Unauthorized);
+            }
+        };
+
+        // Keyed on the stored hash as well, so changing the password invalidates the entry
+        let payload = format!("basic\n{}\n{}", credentials, user.password_hash);
+        let key = hmac::sign(&self.key, payload.as_bytes()).as_ref().to_vec();
+        if !self.recently_verified(&key) {
+            if !verify_password(&user.password_hash, password) {
+                return Err(Error::Unauthorized);
+            }
+
+            let mut verified = self.verified.lock().unwrap();
+            if verified.len() >= VERIFIED_CAPACITY {
+                verified.retain(|_, at| at.elapsed() < VERIFIED_TTL);
+                if verified.len() >= VERIFIED_CAPACITY {
+                    verified.clear();
+                }
+            }
+            verified.insert(key, Instant::now());
+        }
+
+        Ok(Session {
+            user,
+            token: header.to_string(),
+        })
+    }
+
+    fn recently_verified(&self, key: &[u8]) -> bool {
+        let verified = self.verified.lock().unwrap();
+        match verified.get(key) {
+            Some(at) => at.elapsed() < VERIFIED_TTL,
+            None => false,
+        }
+    }
+
+    /// Token to embed in forms for mutating routes
+    pub fn csrf_token(&self, session: &Session) -> String {
+        encode(hmac::sign(&self.key, &csrf_payload(session)).as_ref())
+    }
+
+    pub fn verify_csrf(&self, session: &Session, token: &str) -> Result<(), Error> {
+        let tag = decode(token).ok_or(Error::Forbidden)?;
+        hmac::verify(&self.key, &csrf_payload(session), &tag).map_err(|_| Error::Forbidden)
+    }
+
+    async fn user(&self, db: &Client, name: &str) -> Result<Option<UserConfig>, Error> {
+        if let Some(user) = self.users.iter().find(|u| u.name == name) {
+            return Ok(Some(user.clone()));
+        }
+
+        let row = db
+            .query_opt(
+                "SELECT name, password_hash, accounts FROM web_users WHERE name = $1",
+                &[&name],
+            )
+            .await?;
+        Ok(row.map(|row| UserConfig {
+            name: row.get(0),
+            password_hash: row.get(1),
+            accounts: row.get(2),
+        }))
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+            COOKIE_NAME, value, max_age
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        cookie
+    }
+}
+
+pub struct Session {
+    pub user: UserConfig,
+    token: String,
+}
+
+/// The user's session generation, which is increased on every logout
+async fn generation(db: &Client, name: &str) -> Result<i64, Error> {
+    let row = db
+        .query_opt(
+            "SELECT generation FROM web_sessions WHERE name = $1",
+            &[&name],
+        )
+        .await?;
+    Ok(row.map(|row| row.get(0)).unwrap_or(0))
+}
+
+/// Add a user to the `web_users` table, replacing any user with the same name
+pub async fn store_user(db: &Client, user: &UserConfig) -> Result<(), Error> {
+    db.execute(
+        "INSERT INTO web_users (name, password_hash, accounts) VALUES ($1, $2, $3) \
+         ON CONFLICT (name) DO UPDATE SET password_hash = EXCLUDED.password_hash, \
+         accounts = EXCLUDED.accounts",
+        &[&user.name, &user.password_hash, &user.accounts],
+    )
+    .await?;
+    Ok(())
+}
+
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; 16];
+    SystemRandom::new().fill(&mut salt).unwrap();
+    let mut hash = [0; digest::SHA256_OUTPUT_LEN];
+    let iterations = NonZeroU32::new(PBKDF2_ITERATIONS).unwrap();
+    pbkdf2::derive(
+        PBKDF2_ALG,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    format!(
+        "{}${}${}${}",
+        HASH_SCHEME,
+        PBKDF2_ITERATIONS,
+        encode(&salt),
+        encode(&hash)
+    )
+}
+
+fn verify_password(stored: &str, password: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (scheme, iterations, salt, hash) =
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(scheme), Some(iterations), Some(salt), Some(hash)) => {
+                (scheme, iterations, salt, hash)
+            }
+            _ => return false,
+        };
+
+    if scheme != HASH_SCHEME {
+        return false;
+    }
+
+    let iterations = match iterations.parse().ok().and_then(NonZeroU32::new) {
+        Some(n) => n,
+        None => return false,
+    };
+
+    match (decode(salt), decode(hash)) {
+        (Some(salt), Some(hash)) => {
+            pbkdf2::verify(PBKDF2_ALG, iterations, &salt, password.as_bytes(), &hash).is_ok()
+        }
+        _ => false,
+    }
+}
+
+fn session_cookie(req: &Parts) -> Option<&str> {
+    req.headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(';'))
+        .filter_map(|pair| {
+            let mut split = pair.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(COOKIE_NAME), Some(value)) => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn csrf_payload(session: &Session) -> Vec<u8> {
+    format!("csrf\n{}", session.token).into_bytes()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode(data: &str) -> Option<Vec<u8>> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).ok()
+}
+
+const COOKIE_NAME: &str = "session";
+const SESSION_SECONDS: u64 = 14 * 24 * 60 * 60;
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+const PBKDF2_ITERATIONS: u32 = 100_000;
+/// Well-formed hash that matches no password, verified against for unknown users
+const DUMMY_HASH: &str = "pbkdf2-sha256$100000$AAAAAAAAAAAAAAAAAAAAAA$\
+                          AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+const VERIFIED_TTL: Duration = Duration::from_secs(5 * 60);
+const VERIFIED_CAPACITY: usize = 1024;
+static PBKDF2_ALG: pbkdf2::Algorithm = pbkdf2::PBKDF2_HMAC_SHA256;
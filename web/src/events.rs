@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::stream::{self, StreamExt};
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::Body;
+use mailsync::meta::{MessageMeta, MetaStore};
+use mendes::handler;
+use mendes::http::request::Parts;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio_postgres::{AsyncMessage, NoTls};
+use tracing::warn;
+
+use crate::{App, Error, MessageView, Response};
+
+/// Server-Sent Events stream of changes to the INBOX metadata
+#[handler(App)]
+pub async fn events(app: &App, req: &Parts) -> Result<Response, Error> {
+    let session = app.auth.require(&app.db, req).await?;
+    if !session.user.can_access(&app.account) {
+        return Err(Error::Forbidden);
+    }
+
+    let rx = app.events.subscribe();
+    let body = stream::unfold(rx, |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            // The client missed events and should start over from a fresh page
+            Err(RecvError::Lagged(_)) => Arc::new(Event::new("reload", &()).unwrap()),
+            Err(RecvError::Closed) => return None,
+        };
+        let chunk = format!("event: {}\ndata: {}\n\n", event.name, event.data);
+        Some((Ok::<_, Error>(chunk), rx))
+    });
+
+    Ok(hyper::Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(body))?)
+}
+
+/// Start listening for changes to the stored metadata, returning a channel of events
+///
+/// Triggers on the `inbox` table notify listeners when a message is added or removed,
+/// or when its flags change, so this also picks up changes made by `mailsync get-meta`.
+pub fn watch(uri: &str) -> broadcast::Sender<Arc<Event>> {
+    let (tx, _) = broadcast::channel(CHANNEL_SIZE);
+    let sender = tx.clone();
+    let uri = uri.to_string();
+    tokio::spawn(async move {
+        let mut reconnected = false;
+        loop {
+            match listen(&uri, &sender, reconnected).await {
+                Ok(()) => warn!("store notifications stopped"),
+                Err(e) => warn!(error = %e, "failed to listen for store notifications"),
+            }
+            tokio::time::delay_for(RECONNECT_DELAY).await;
+            reconnected = true;
+        }
+    });
+    tx
+}
+
+/// Forward notifications to subscribers until the connection fails
+async fn listen(
+    uri: &str,
+    sender: &broadcast::Sender<Arc<Event>>,
+    reconnected: bool,
+) -> Result<(), Error> {
+    let (db, mut connection) = tokio_postgres::connect(uri, NoTls).await?;
+    let (tx, mut rx) = mpsc::unbounded();
+    let messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    tokio::spawn(messages.map(Ok).forward(tx));
+
+    db.batch_execute("LISTEN inbox").await?;
+    if reconnected {
+        // Changes made while disconnected were missed
+        let _ = sender.send(Arc::new(Event::new("reload", &())?));
+    }
+
+    let store = MetaStore::new(&db);
+    while let Some(message) = rx.next().await {
+        let notification = match message? {
+            AsyncMessage::Notification(notification) => notification,
+            _ => continue,
+        };
+
+        match derive(&store, notification.payload()).await {
+            // Sending only fails if there are currently no subscribers
+            Ok(Some(event)) => {
+                let _ = sender.send(Arc::new(event));
+            }
+            Ok(None) => {}
+            Err(e) => warn!(error = %e, "failed to process store notification"),
+        }
+    }
+    Ok(())
+}
+
+/// Turn a notification from the `inbox` triggers into an event for subscribers
+async fn derive(store: &MetaStore<'_>, payload: &str) -> Result<Option<Event>, Error> {
+    let mut parts = payload.splitn(2, ' ');
+    let (name, uid) = match (parts.next(), parts.next().map(str::parse::<u32>)) {
+        (Some(name), Some(Ok(uid))) => (name, uid),
+        _ => return Ok(None),
+    };
+
+    let name = match name {
+        "message" => "message",
+        "flags" => "flags",
+        "removed" => return Ok(Some(Event::new("removed", &Removed { uid })?)),
+        _ => return Ok(None),
+    };
+
+    // The message may have been removed again since the notification was sent
+    match store.get(uid).await? {
+        Some(meta) => Ok(Some(Event::new(name, &Row::from(&meta))?)),
+        None => Ok(None),
+    }
+}
+
+pub struct Event {
+    name: &'static str,
+    data: String,
+}
+
+impl Event {
+    fn new<T: Serialize>(name: &'static str, data: &T) -> Result<Self, Error> {
+        Ok(Self {
+            name,
+            data: serde_json::to_string(data).map_err(Error::Json)?,
+        })
+    }
+}
+
+/// The fields needed to render or update a row in the inbox table
+#[derive(Serialize)]
+struct Row<'a> {
+    uid: u32,
+    unread: bool,
+    flagged: bool,
+    sender: &'a str,
+    subject: &'a str,
+    date: String,
+}
+
+impl<'a> From<&'a MessageMeta> for Row<'a> {
+    fn from(meta: &'a MessageMeta) -> Self {
+        Self {
+            uid: meta.uid,
+            unread: meta.unread(),
+            flagged: meta.flagged(),
+            sender: meta.sender_name(),
+            subject: MessageView::subject(meta),
+            date: meta.date(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Removed {
+    uid: u32,
+}
+
+const CHANNEL_SIZE: usize = 256;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
@@ -1,59 +1,233 @@
+use std::convert::TryFrom;
+use std::io::{self, BufRead};
+use std::sync::Arc;
+
 use askama::Template;
 use async_trait::async_trait;
-use chrono::{DateTime, FixedOffset, Utc};
+use chrono::Utc;
 use err_derive::Error;
-use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION, SET_COOKIE};
 use hyper::Body;
-use mailsync::Flag;
+use mailsync::connection::Session;
+use mailsync::credentials;
+use mailsync::meta::{Action, MessageMeta, MetaStore};
+use mailsync::{
+    fetch, logging, metrics, schema, Config, Flag, ImapConfig, SyncError, UserConfig, WebConfig,
+};
 use mendes::http::{request::Parts, StatusCode};
 use mendes::{dispatch, handler, types, Application, ClientError, Context};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use structopt::StructOpt;
+use tokio::sync::{broadcast, Mutex};
+use tokio_postgres::NoTls;
+use tracing::{error, info, warn};
+
+mod api;
+mod auth;
+use auth::Auth;
+mod events;
 
 #[tokio::main]
 async fn main() {
-    mendes::hyper::run(&"[::]:3000".parse().unwrap(), App {})
-        .await
-        .unwrap();
+    let options = Options::from_args();
+    match options.command {
+        Some(Command::HashPassword) => {
+            println!("{}", auth::hash_password(&read_password()));
+            return;
+        }
+        Some(Command::AddUser { name }) => {
+            let config = Config::from_file(&options.config);
+            let user = UserConfig {
+                name,
+                password_hash: auth::hash_password(&read_password()),
+                accounts: None,
+            };
+            let db = connect(&config.store.uri).await.unwrap();
+            auth::store_user(&db, &user).await.unwrap();
+            println!("added user {}", user.name);
+            return;
+        }
+        None => {}
+    }
+
+    logging::init(options.verbose);
+    let app = App::new(Config::from_file(&options.config)).await.unwrap();
+    let addr = app.config.listen;
+    info!(%addr, "listening");
+    mendes::hyper::run(&addr, app).await.unwrap();
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "web")]
+struct Options {
+    /// Path to the configuration file
+    #[structopt(short, long, default_value = "mailsync.toml", global = true)]
+    config: String,
+    /// Log more details (repeat for even more)
+    #[structopt(short, long, parse(from_occurrences))]
+    verbose: u8,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Hash a password read from stdin, for the `users` list in the configuration
+    HashPassword,
+    /// Add a user to the database, with a password read from stdin
+    AddUser {
+        /// Name to log in with
+        name: String,
+    },
+}
+
+/// Connect to the database and bring its schema up to date
+async fn connect(uri: &str) -> Result<tokio_postgres::Client, Error> {
+    let (db, connection) = tokio_postgres::connect(uri, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            error!(error = %e, "database connection error");
+        }
+    });
+    schema::migrate_async(&db).await?;
+    Ok(db)
+}
+
+fn read_password() -> String {
+    eprintln!("password:");
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password).unwrap();
+    password.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
+#[handler(App)]
+async fn login_form(app: &App, _: &Parts) -> Result<Response, Error> {
+    app.templated(Login { failed: false })
+}
+
+#[handler(App)]
+async fn login(app: &App, _: &Parts, #[body] form: LoginForm) -> Result<Response, Error> {
+    match app.auth.login(&app.db, &form.name, &form.password).await? {
+        Some(cookie) => Ok(hyper::Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, "/")
+            .header(SET_COOKIE, cookie)
+            .body(Body::empty())?),
+        None => app.templated(Login { failed: true }),
+    }
+}
+
+#[handler(App)]
+async fn logout(app: &App, req: &Parts, #[body] form: CsrfForm) -> Result<Response, Error> {
+    let session = app.auth.require(&app.db, req).await?;
+    app.auth.verify_csrf(&session, &form.csrf)?;
+    Ok(hyper::Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/login")
+        .header(SET_COOKIE, app.auth.logout(&app.db, &session).await?)
+        .body(Body::empty())?)
+}
+
+#[handler(App)]
+async fn ui(app: &App, req: &Parts) -> Result<Response, Error> {
+    let session = match app.auth.session(&app.db, req).await? {
+        Some(session) => session,
+        None => {
+            return Ok(hyper::Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(LOCATION, "/login")
+                .body(Body::empty())?)
+        }
+    };
+    if !session.user.can_access(&app.account) {
+        return Err(Error::Forbidden);
+    }
+
+    let messages = app.store().latest(app.config.page_size).await?;
+    let csrf = app.auth.csrf_token(&session);
+    app.templated(Mailbox { messages, csrf })
+}
+
+#[handler(App)]
+async fn action(app: &App, req: &Parts, #[body] form: ActionForm) -> Result<Response, Error> {
+    let session = app.auth.require(&app.db, req).await?;
+    app.auth.verify_csrf(&session, &form.csrf)?;
+    if !session.user.can_access(&app.account) {
+        return Err(Error::Forbidden);
+    }
+
+    let action = match (form.action.as_str(), form.label) {
+        ("read", _) => Action::AddFlag(Flag::Seen),
+        ("unread", _) => Action::RemoveFlag(Flag::Seen),
+        ("flag", _) => Action::AddFlag(Flag::Flagged),
+        ("unflag", _) => Action::RemoveFlag(Flag::Flagged),
+        ("archive", _) => Action::Move(app.archive.clone()),
+        ("label", Some(label)) if !label.trim().is_empty() => {
+            Action::Copy(label.trim().to_string())
+        }
+        _ => return Err(Error::BadRequest),
+    };
+
+    app.store().apply(form.uid, action).await?;
+    Ok(hyper::Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, "/")
+        .body(Body::empty())?)
 }
 
+/// Prometheus metrics for this process, plus sync health as recorded by `mailsync sync`
+///
+/// Scrapers authenticate like API clients, with HTTP Basic credentials.
 #[handler(App)]
-async fn ui(app: &App, _: &Parts) -> Result<Response, Error> {
-    let db = sled::open("mail.sled").unwrap();
-    let meta = db.open_tree("meta").unwrap();
-    let messages = meta
-        .iter()
-        .values()
-        .rev()
-        .take(100)
-        .map(|val| val.map(|v| bincode::deserialize(&v).unwrap()))
-        .collect::<Result<Vec<_>, _>>()
-        .unwrap();
-    app.templated(Mailbox { messages })
+async fn export_metrics(app: &App, req: &Parts) -> Result<Response, Error> {
+    app.auth.api_session(&app.db, req).await?;
+    metrics::load_status(&app.db).await?;
+    let body = metrics::encode();
+    Ok(hyper::Response::builder()
+        .header(CONTENT_TYPE, metrics::TEXT_FORMAT)
+        .header(CONTENT_LENGTH, body.len())
+        .body(body.into())?)
 }
 
 #[derive(Template)]
 #[template(path = "index.html")]
 struct Mailbox {
     messages: Vec<MessageMeta>,
+    csrf: String,
 }
 
-#[derive(Deserialize, Serialize)]
-struct MessageMeta {
-    seq: u32,
+#[derive(Template)]
+#[template(path = "login.html")]
+struct Login {
+    failed: bool,
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    name: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct ActionForm {
+    csrf: String,
     uid: u32,
-    mod_seq: u64,
-    flags: Vec<Flag>,
-    mid: Option<String>,
-    dt: Option<DateTime<FixedOffset>>,
-    subject: Option<String>,
-    sender: Option<String>,
+    action: String,
+    label: Option<String>,
 }
 
-impl MessageMeta {
-    fn unread(&self) -> bool {
-        !self.flags.contains(&Flag::Seen)
-    }
+trait MessageView {
+    fn sender_name(&self) -> &str;
+    fn date(&self) -> String;
+    fn subject(&self) -> &str;
+}
 
+impl MessageView for MessageMeta {
     fn sender_name(&self) -> &str {
         match &self.sender {
             Some(s) => {
@@ -88,22 +262,103 @@ impl MessageMeta {
     }
 
     fn subject(&self) -> &str {
-        let mut s: &str = match self.subject.as_ref() {
-            Some(s) => s,
-            None => return "(no subject)",
-        };
-
-        if s.starts_with("Re: ") {
-            s = &s[4..];
+        match self.subject.as_ref() {
+            Some(s) => normalize_subject(s),
+            None => "(no subject)",
         }
+    }
+}
 
-        s
+/// Strip reply and forward prefixes so related messages share a subject
+fn normalize_subject(mut s: &str) -> &str {
+    loop {
+        let trimmed = s.trim_start();
+        let lower = trimmed.get(..4).map(|p| p.to_ascii_lowercase());
+        s = match lower.as_deref() {
+            Some("re: ") | Some("fw: ") => &trimmed[4..],
+            Some("fwd:") => &trimmed[4..],
+            _ => return trimmed,
+        };
     }
 }
 
-struct App {}
+struct App {
+    db: tokio_postgres::Client,
+    events: broadcast::Sender<Arc<events::Event>>,
+    auth: Auth,
+    account: String,
+    archive: String,
+    config: WebConfig,
+    /// For downloading bodies of messages that were synced with headers only
+    imap: ImapConfig,
+    credentials: Option<credentials::Auth>,
+    /// Shared by all requests, so that viewing messages doesn't log in for each of them
+    session: Mutex<Option<Session>>,
+}
 
 impl App {
+    async fn new(config: Config) -> Result<Self, Error> {
+        let db = connect(&config.store.uri).await?;
+        let credentials = match config.imap.auth() {
+            Ok(auth) => Some(auth),
+            Err(e) => {
+                warn!(error = %e, "no IMAP credentials, message bodies can't be downloaded");
+                None
+            }
+        };
+        Ok(Self {
+            events: events::watch(&config.store.uri),
+            db,
+            auth: Auth::new(&config.web)?,
+            account: config.imap.account.clone(),
+            archive: config.imap.archive.clone(),
+            config: config.web,
+            imap: config.imap,
+            credentials,
+            session: Mutex::new(None),
+        })
+    }
+
+    fn store(&self) -> MetaStore<'_> {
+        MetaStore::new(&self.db)
+    }
+
+    /// Download the body of a message that was synced with headers only
+    ///
+    /// Returns `None` if the message is no longer on the server.
+    async fn fill_body(&self, id: i32, uid: u32) -> Result<Option<Vec<u8>>, Error> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or(Error::Config("no IMAP credentials"))?;
+        let chunk_len = u32::try_from(self.imap.max_in_flight)
+            .map_err(|_| Error::Config("max_in_flight is too large"))?;
+
+        // Requests wait for each other here, rather than each opening a connection
+        let mut shared = self.session.lock().await;
+        loop {
+            let reused = shared.is_some();
+            if !reused {
+                let mut session = mailsync::login(&self.imap, credentials).await?;
+                fetch::examine_sync_folder(&mut session.client).await?;
+                *shared = Some(session);
+            }
+
+            let session = shared.as_mut().unwrap();
+            match fetch::fill_body(&mut session.client, &self.db, id, uid, chunk_len).await {
+                Ok(source) => return Ok(source),
+                Err(e) => {
+                    *shared = None;
+                    // The server may have closed the session while it was idle
+                    match reused {
+                        true => warn!(error = ?e, "IMAP session failed, logging in again"),
+                        false => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    }
+
     fn templated<T: Template>(&self, t: T) -> Result<Response, Error> {
         let content = t.render()?;
         Ok(hyper::Response::builder()
@@ -122,14 +377,30 @@ impl Application for App {
     #[dispatch]
     async fn handle(mut cx: Context<Self>) -> Response {
         path! {
+            "login" => method! {
+                GET => login_form,
+                POST => login,
+            },
+            "logout" => method! {
+                POST => logout,
+            },
+            "action" => method! {
+                POST => action,
+            },
+            "api" => api::api,
+            "events" => events::events,
+            "metrics" => method! {
+                GET => export_metrics,
+            },
             _ => ui,
         }
     }
 
-    fn error(&self, _: Error) -> Response {
+    fn error(&self, e: Error) -> Response {
+        let status = e.status();
         hyper::Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body("ERROR".into())
+            .status(status)
+            .body(status.canonical_reason().unwrap_or("ERROR").into())
             .unwrap()
     }
 }
@@ -138,10 +409,68 @@ impl Application for App {
 enum Error {
     #[error(display = "client error: {:?}", _0)]
     Client(#[source] ClientError),
+    #[error(display = "invalid configuration: {}", _0)]
+    Config(&'static str),
     #[error(display = "http error: {:?}", _0)]
     Http(#[source] mendes::http::Error),
+    #[error(display = "serialization error: {:?}", _0)]
+    Json(#[source] serde_json::Error),
+    #[error(display = "database error: {:?}", _0)]
+    Pg(#[source] tokio_postgres::Error),
     #[error(display = "template error: {:?}", _0)]
     Template(#[source] askama::Error),
+    #[error(display = "sync error: {}", _0)]
+    Sync(String),
+    #[error(display = "invalid request")]
+    BadRequest,
+    #[error(display = "not logged in")]
+    Unauthorized,
+    #[error(display = "access denied")]
+    Forbidden,
+    #[error(display = "not found")]
+    NotFound,
+    #[error(display = "method not allowed")]
+    MethodNotAllowed,
+}
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::Client(e) => StatusCode::from(e),
+            Error::BadRequest => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            e => {
+                error!(error = %e, "request failed");
+                metrics::ERRORS.with_label_values(&[e.kind()]).inc();
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Short name for the kind of internal error, as used in metrics
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "config",
+            Error::Http(_) => "http",
+            Error::Json(_) => "json",
+            Error::Pg(_) => "postgres",
+            Error::Template(_) => "template",
+            Error::Sync(_) => "sync",
+            _ => "client",
+        }
+    }
+}
+
+impl From<SyncError> for Error {
+    fn from(e: SyncError) -> Self {
+        match e {
+            SyncError::Pg(e) => Error::Pg(e),
+            e => Error::Sync(format!("{:?}", e)),
+        }
+    }
 }
 
 type Response = mendes::http::Response<Body>;